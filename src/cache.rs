@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use {warning, write_atomically, CookieAndCsrf, DarkError};
+
+// The server's session cookie has a Max-Age of a week; don't bother trying a cached session
+// that's older than that.
+const MAX_AGE_SECS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Deserialize, Serialize)]
+struct CachedSession {
+    cookie: String,
+    csrf: String,
+    saved_at: u64,
+}
+
+// The cache file: sessions keyed by user@host, so switching accounts or hosts doesn't reuse the
+// wrong one.
+type Sessions = BTreeMap<String, CachedSession>;
+
+fn path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("dark-cli").join("sessions.json"))
+}
+
+fn key(host: &str, user: &str) -> String {
+    format!("{}@{}", user, host)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or(0)
+}
+
+// Read the cache file. A missing file is just an empty cache; one we can't parse (say, it was
+// cut short by a crash) is deleted, with a warning, so the next save starts it over cleanly.
+fn read() -> Sessions {
+    let path = match path() {
+        Some(path) => path,
+        None => return Sessions::new(),
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(_) => return Sessions::new(),
+    };
+    serde_json::from_str(&contents).unwrap_or_else(|err| {
+        warning::push(format!(
+            "ignored the unreadable session cache {} ({}) and logged in again.",
+            path.display(),
+            err
+        ));
+        let _ = fs::remove_file(&path);
+        Sessions::new()
+    })
+}
+
+// The cached session for `user` on `host`, if there's one young enough to still be good.
+pub(crate) fn load(host: &str, user: &str) -> Option<CookieAndCsrf> {
+    read()
+        .remove(&key(host, user))
+        .filter(|session| now().saturating_sub(session.saved_at) < MAX_AGE_SECS)
+        .map(|session| CookieAndCsrf {
+            cookie: session.cookie,
+            csrf: session.csrf,
+            msg: None,
+        })
+}
+
+// Remember `session` for `user` on `host`. The file is written via a temp file and a rename, so
+// a crash or a concurrent run can't leave half a file behind. Failing to save only costs a login
+// next time, so it's a warning rather than an error.
+pub(crate) fn save(host: &str, user: &str, session: &CookieAndCsrf) {
+    let path = match path() {
+        Some(path) => path,
+        None => return,
+    };
+    let mut sessions = read();
+    sessions.insert(
+        key(host, user),
+        CachedSession {
+            cookie: session.cookie.clone(),
+            csrf: session.csrf.clone(),
+            saved_at: now(),
+        },
+    );
+    let contents = serde_json::to_string_pretty(&sessions).unwrap_or_default();
+    let saved = match path.parent() {
+        Some(dir) => create_private_dir(dir).map_err(|err| err.to_string()),
+        None => Ok(()),
+    }
+    .and_then(|_| write_atomically(&path, contents.as_bytes()).map_err(|err| err.to_string()));
+    if let Err(err) = saved {
+        warning::push(format!("couldn't save the session cache: {}", err));
+    }
+}
+
+// Drop the cached session for `user` on `host`, once the server has turned it down.
+pub(crate) fn forget(host: &str, user: &str) {
+    let path = match path() {
+        Some(path) => path,
+        None => return,
+    };
+    let mut sessions = read();
+    if sessions.remove(&key(host, user)).is_none() {
+        return;
+    }
+    let contents = serde_json::to_string_pretty(&sessions).unwrap_or_default();
+    if let Err(err) = write_atomically(&path, contents.as_bytes()) {
+        warning::push(format!("couldn't update the session cache: {}", err));
+    }
+}
+
+// Drop every cached session for `host`, or for every host if it's None, for `dark logout`.
+// Returns the user@host of each session dropped. Unlike the other cache updates, failing here
+// is an error: the user asked for the sessions to be gone.
+pub(crate) fn clear(host: Option<&str>) -> Result<Vec<String>, DarkError> {
+    let path = match path() {
+        Some(path) => path,
+        None => return Ok(vec![]),
+    };
+    let mut sessions = read();
+    let cleared: Vec<String> = sessions
+        .keys()
+        .filter(|key| match host {
+            Some(host) => key.ends_with(&format!("@{}", host)),
+            None => true,
+        })
+        .cloned()
+        .collect();
+    if cleared.is_empty() {
+        return Ok(cleared);
+    }
+    for key in &cleared {
+        sessions.remove(key);
+    }
+    if sessions.is_empty() {
+        fs::remove_file(&path).map_err(|err| DarkError::io(&path, &err))?;
+    } else {
+        let contents = serde_json::to_string_pretty(&sessions).unwrap_or_default();
+        write_atomically(&path, contents.as_bytes())?;
+    }
+    Ok(cleared)
+}
+
+// The cache holds live session tokens, so keep its directory to ourselves.
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+// For `dark doctor`: check that the cache directory can be created and written to, by writing
+// (and removing) a file in it. Returns the directory.
+pub(crate) fn check_writable() -> Result<PathBuf, String> {
+    let dir = path()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .ok_or_else(|| "this system has no cache directory".to_string())?;
+    create_private_dir(&dir)
+        .map_err(|err| format!("couldn't create {}: {}", dir.display(), err))?;
+    let probe = dir.join(format!(".doctor-{}", std::process::id()));
+    write_atomically(&probe, b"")
+        .and_then(|_| fs::remove_file(&probe).map_err(|err| DarkError::io(&probe, &err)))
+        .map_err(|err| err.to_string())?;
+    Ok(dir)
+}
@@ -0,0 +1,292 @@
+// --cache-control: the Cache-Control policy the server should serve each asset with. Dark's
+// static assets API has no metadata of its own, so like --checksums' digests the policy rides
+// along as a text field named after the asset (<name>.cache-control), for servers that honour it.
+// --cache-preset adds rules for the usual cases ahead of those, so any of them can be overridden.
+
+use globset::{GlobBuilder, GlobMatcher};
+
+use DarkError;
+
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+// --cache-preset's rules: a year for fonts, images and names with a content hash in them, which
+// change name when they change, and five minutes for pages, which keep theirs. Hashed comes after
+// the pages, so a hashed page is cached for good too.
+const PRESETS: &[(Preset, &str)] = &[
+    (Preset::Glob("*.{woff2,woff,ttf,otf,eot}"), IMMUTABLE),
+    (
+        Preset::Glob("*.{png,jpg,jpeg,gif,svg,webp,avif,ico}"),
+        IMMUTABLE,
+    ),
+    (Preset::Glob("*.{html,htm}"), "public, max-age=300"),
+    (Preset::Hashed, IMMUTABLE),
+];
+
+enum Preset {
+    Glob(&'static str),
+    Hashed,
+}
+
+// Which files a rule covers.
+enum Rule {
+    // a bare policy
+    Every,
+    Glob(GlobMatcher),
+    // the hashed preset, per looks_hashed
+    Hashed,
+}
+
+impl Rule {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Rule::Every => true,
+            Rule::Glob(glob) => glob.is_match(name),
+            Rule::Hashed => looks_hashed(name),
+        }
+    }
+}
+
+// The --cache-preset rules, then the --cache-control ones in the order they were given.
+pub(crate) struct CacheControl {
+    rules: Vec<(Rule, String)>,
+}
+
+impl CacheControl {
+    // Parse the --cache-control values: a bare policy ('public, max-age=3600'), or a glob and a
+    // policy ('*.js=public, max-age=31536000'). The glob, matched against the asset name like an
+    // --exclude pattern, is the part before the first '=' when that has a glob character, a dot
+    // or a slash in it, which no standard cache directive's name has.
+    pub(crate) fn new(
+        values: &[&str],
+        presets: bool,
+        ignore_case: bool,
+    ) -> Result<CacheControl, DarkError> {
+        let invalid = |value: &str, reason| {
+            DarkError::InvalidArgument(
+                "cache-control".to_string(),
+                format!("{:?} {}", value, reason),
+            )
+        };
+        let mut rules = vec![];
+        if presets {
+            for &(ref preset, policy) in PRESETS {
+                let rule = match preset {
+                    // an extension's case never matters here
+                    Preset::Glob(glob) => Rule::Glob(
+                        GlobBuilder::new(glob)
+                            .case_insensitive(true)
+                            .build()
+                            .expect("the preset globs are valid")
+                            .compile_matcher(),
+                    ),
+                    Preset::Hashed => Rule::Hashed,
+                };
+                rules.push((rule, policy.to_string()));
+            }
+        }
+        for &value in values {
+            let (glob, policy) = split_rule(value);
+            check_policy(policy).map_err(|reason| invalid(value, reason))?;
+            let rule = match glob {
+                Some(glob) => Rule::Glob(
+                    GlobBuilder::new(glob)
+                        .case_insensitive(ignore_case)
+                        .build()
+                        .map_err(|err| invalid(value, format!("has an invalid glob: {}", err)))?
+                        .compile_matcher(),
+                ),
+                None => Rule::Every,
+            };
+            rules.push((rule, policy.to_string()));
+        }
+        Ok(CacheControl { rules })
+    }
+
+    // The policy for the asset named `name`: the last rule that matches it, so a general rule
+    // can come first and more particular ones after it.
+    pub(crate) fn policy(&self, name: &str) -> Option<&str> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|&&(ref rule, _)| rule.matches(name))
+            .map(|&(_, ref policy)| policy.as_str())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+// The file type `name` is listed under in the -v summary of policies: its extension, and whether
+// it looks hashed, since the presets treat app.js and app.3f2a9c1b.js differently.
+pub(crate) fn file_type(name: &str) -> String {
+    let file = name.rsplit('/').next().unwrap_or(name);
+    let extension = match file.rfind('.') {
+        // a leading dot (.htaccess) isn't an extension
+        Some(dot) if dot > 0 => format!(".{}", file[dot + 1..].to_lowercase()),
+        _ => "(none)".to_string(),
+    };
+    if looks_hashed(name) {
+        format!("hashed {}", extension)
+    } else {
+        extension
+    }
+}
+
+// Whether the file name has a content hash in it, as bundlers and --name-from-hash add them: a
+// part of it other than the extension, split at dots and dashes, of 8 or more hex digits.
+fn looks_hashed(name: &str) -> bool {
+    let file = name.rsplit('/').next().unwrap_or(name);
+    let stem = match file.rfind('.') {
+        Some(dot) if dot > 0 => &file[..dot],
+        _ => file,
+    };
+    stem.split(|c| c == '.' || c == '-')
+        .any(|part| part.len() >= 8 && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn split_rule(value: &str) -> (Option<&str>, &str) {
+    match value.find('=') {
+        Some(equals) if value[..equals].contains(|c| "*?[{./".contains(c)) => {
+            (Some(&value[..equals]), value[equals + 1..].trim())
+        }
+        _ => (None, value.trim()),
+    }
+}
+
+// Whether `c` can be part of a token (RFC 9110), as directive names and most values are.
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+// Check that `policy` is a Cache-Control value: comma-separated directives, each a token,
+// optionally followed by '=' and a token or a quoted string.
+fn check_policy(policy: &str) -> Result<(), String> {
+    if policy.is_empty() {
+        return Err("has no policy".to_string());
+    }
+    let mut rest = policy;
+    loop {
+        let name_len = rest
+            .find(|c| !is_token_char(c))
+            .unwrap_or_else(|| rest.len());
+        if name_len == 0 {
+            return Err(format!("has no directive before {:?}", rest));
+        }
+        rest = &rest[name_len..];
+        if rest.starts_with('=') {
+            rest = &rest[1..];
+            if rest.starts_with('"') {
+                let close = quoted_len(rest).ok_or("has an unterminated quoted string")?;
+                rest = &rest[close..];
+            } else {
+                let value_len = rest
+                    .find(|c| !is_token_char(c))
+                    .unwrap_or_else(|| rest.len());
+                if value_len == 0 {
+                    return Err("has a directive with '=' but no value".to_string());
+                }
+                rest = &rest[value_len..];
+            }
+        }
+        rest = rest.trim_start_matches(|c| c == ' ' || c == '\t');
+        if rest.is_empty() {
+            return Ok(());
+        }
+        if !rest.starts_with(',') {
+            return Err(format!("has {:?} where a ',' should be", rest));
+        }
+        rest = rest[1..].trim_start_matches(|c| c == ' ' || c == '\t');
+    }
+}
+
+// The length of the quoted string `rest` starts with, quotes included; None if it doesn't end.
+fn quoted_len(rest: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (index, c) in rest.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(index + 1),
+            _ if c.is_control() => return None,
+            _ => (),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_last_matching_rule_wins() {
+        let rules = CacheControl::new(
+            &[
+                "public, max-age=300",
+                "*.js=public, max-age=31536000, immutable",
+                "sw.js=no-cache",
+            ],
+            false,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            rules.policy("js/app.js"),
+            Some("public, max-age=31536000, immutable")
+        );
+        assert_eq!(rules.policy("sw.js"), Some("no-cache"));
+        assert_eq!(rules.policy("index.html"), Some("public, max-age=300"));
+        assert_eq!(
+            CacheControl::new(&[], false, false).unwrap().policy("a.js"),
+            None
+        );
+    }
+
+    #[test]
+    fn policies_are_checked_as_cache_control_values() {
+        assert_eq!(check_policy("no-store"), Ok(()));
+        assert_eq!(check_policy("public,max-age=60 , s-maxage=\"120\""), Ok(()));
+        assert_eq!(check_policy("private=\"Set-Cookie, X-A\""), Ok(()));
+        assert!(check_policy("").is_err());
+        assert!(check_policy("max-age=").is_err());
+        assert!(check_policy("max age=60").is_err());
+        assert!(check_policy("public,,private").is_err());
+        assert!(check_policy("private=\"unterminated").is_err());
+        assert!(check_policy("no-cache\r\nX-Injected: 1").is_err());
+        match CacheControl::new(&["*.js=max-age=1 year"], false, false) {
+            Err(DarkError::InvalidArgument(flag, reason)) => {
+                assert_eq!(flag, "cache-control");
+                assert!(
+                    reason.starts_with("\"*.js=max-age=1 year\" has"),
+                    "{}",
+                    reason
+                );
+            }
+            _ => panic!("an invalid policy was accepted"),
+        }
+    }
+
+    #[test]
+    fn presets_cache_hashed_names_fonts_and_images_for_good_and_pages_briefly() {
+        let presets = CacheControl::new(&[], true, false).unwrap();
+        assert_eq!(presets.policy("fonts/Inter.WOFF2"), Some(IMMUTABLE));
+        assert_eq!(presets.policy("img/logo.svg"), Some(IMMUTABLE));
+        assert_eq!(presets.policy("js/app.3f2a9c1b.js"), Some(IMMUTABLE));
+        assert_eq!(presets.policy("js/chunk-0123abcd.js"), Some(IMMUTABLE));
+        assert_eq!(presets.policy("0123abcd"), Some(IMMUTABLE));
+        assert_eq!(presets.policy("index.html"), Some("public, max-age=300"));
+        assert_eq!(presets.policy("index.0123abcdef.html"), Some(IMMUTABLE));
+        // too short, not all hex, or only the extension
+        assert_eq!(presets.policy("js/app.0123abc.js"), None);
+        assert_eq!(presets.policy("js/app.0123abcx.js"), None);
+        assert_eq!(presets.policy("data/feed.0123abcd"), None);
+        assert_eq!(presets.policy("data/feed.json"), None);
+
+        let overridden = CacheControl::new(&["*.html=no-cache"], true, false).unwrap();
+        assert_eq!(overridden.policy("index.html"), Some("no-cache"));
+        assert_eq!(overridden.policy("img/logo.png"), Some(IMMUTABLE));
+        assert_eq!(file_type("js/app.3f2a9c1b.JS"), "hashed .js");
+        assert_eq!(file_type(".htaccess"), "(none)");
+    }
+}
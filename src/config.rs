@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use DarkError;
+
+// The project config file, looked for in the current directory.
+const CONFIG_FILE: &str = "dark.toml";
+// The project marker file, looked for in the current directory and each one above it; a
+// lightweight way to pin a project to a canvas and host (like .nvmrc).
+const MARKER_FILE: &str = ".dark";
+// The machine-wide config file, skipped with --no-system-config.
+const SYSTEM_CONFIG_FILE: &str = "/etc/dark/config.toml";
+
+// Settings that can come from the config file, at the top level or in a [profile.<name>] table.
+// Flags always win over these.
+#[derive(Deserialize, Default, Clone)]
+pub(crate) struct Settings {
+    pub host: Option<String>,
+    pub canvas: Option<String>,
+    // cache = false turns off the session cache, like --no-cache
+    pub cache: Option<bool>,
+    // credentials for particular hosts, keyed by the host's URL: [hosts."https://example.com"]
+    #[serde(default)]
+    pub hosts: BTreeMap<String, HostCredentials>,
+}
+
+// A [hosts."<url>"] table: the credentials for a host, and where it logs in (login_url, for a
+// self-hosted instance that doesn't log in at <url>/dark-cli). Dark logs in with a username and
+// password; `token` is only read so that setting it can be reported, rather than being silently
+// ignored.
+#[derive(Deserialize, Default, Clone)]
+pub(crate) struct HostCredentials {
+    user: Option<String>,
+    password: Option<String>,
+    token: Option<String>,
+    login_url: Option<String>,
+}
+
+impl HostCredentials {
+    fn or(self, defaults: HostCredentials) -> HostCredentials {
+        HostCredentials {
+            user: self.user.or(defaults.user),
+            password: self.password.or(defaults.password),
+            token: self.token.or(defaults.token),
+            login_url: self.login_url.or(defaults.login_url),
+        }
+    }
+}
+
+impl Settings {
+    // `self`, with anything unset filled in from `defaults`.
+    fn or(self, defaults: Settings) -> Settings {
+        Settings {
+            host: self.host.or(defaults.host),
+            canvas: self.canvas.or(defaults.canvas),
+            cache: self.cache.or(defaults.cache),
+            hosts: merge_hosts(self.hosts, defaults.hosts),
+        }
+    }
+
+    // The login URL the config files give for `host`, if any.
+    pub(crate) fn login_url(&self, host: &str) -> Option<String> {
+        self.hosts
+            .iter()
+            .find(|&(url, _)| url.trim_end_matches('/') == host)
+            .and_then(|(_, host)| host.login_url.clone())
+    }
+
+    // The username and password the config files give for `host`, if any.
+    pub(crate) fn credentials(&self, host: &str) -> Result<Option<(String, String)>, DarkError> {
+        let (url, credentials) = match self
+            .hosts
+            .iter()
+            .find(|&(url, _)| url.trim_end_matches('/') == host)
+        {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let invalid = |detail: &str| DarkError::HostCredentials(url.clone(), detail.to_string());
+        if credentials.token.is_some() {
+            return Err(invalid(
+                "Dark logs in with a username and password, so there's nothing to send a token to; set user and password instead",
+            ));
+        }
+        match (&credentials.user, &credentials.password) {
+            (Some(user), Some(password)) => Ok(Some((user.clone(), password.clone()))),
+            (Some(_), None) => Err(invalid("there's a user, but no password")),
+            (None, Some(_)) => Err(invalid("there's a password, but no user")),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+// `ours`, with the hosts only in `defaults` added, and anything unset in a host we both have
+// filled in from theirs.
+fn merge_hosts(
+    mut ours: BTreeMap<String, HostCredentials>,
+    defaults: BTreeMap<String, HostCredentials>,
+) -> BTreeMap<String, HostCredentials> {
+    for (url, credentials) in defaults {
+        let merged = match ours.remove(&url) {
+            Some(our_credentials) => our_credentials.or(credentials),
+            None => credentials,
+        };
+        ours.insert(url, merged);
+    }
+    ours
+}
+
+#[derive(Deserialize, Default)]
+struct ConfigFile {
+    #[serde(flatten)]
+    defaults: Settings,
+    #[serde(default)]
+    profile: BTreeMap<String, Settings>,
+}
+
+impl ConfigFile {
+    // `self`, with anything unset (at the top level, or in any profile) filled in from `base`.
+    fn or(mut self, base: ConfigFile) -> ConfigFile {
+        for (name, settings) in base.profile {
+            let merged = match self.profile.remove(&name) {
+                Some(ours) => ours.or(settings),
+                None => settings,
+            };
+            self.profile.insert(name, merged);
+        }
+        ConfigFile {
+            defaults: self.defaults.or(base.defaults),
+            profile: self.profile,
+        }
+    }
+}
+
+// A .dark marker only pins a canvas and host; anything else in one is an error rather than a
+// setting that's quietly read (profiles and credentials belong in dark.toml or the user config).
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Marker {
+    canvas: Option<String>,
+    host: Option<String>,
+}
+
+impl From<Marker> for ConfigFile {
+    fn from(marker: Marker) -> ConfigFile {
+        ConfigFile {
+            defaults: Settings {
+                host: marker.host,
+                canvas: marker.canvas,
+                ..Settings::default()
+            },
+            profile: BTreeMap::new(),
+        }
+    }
+}
+
+// The nearest .dark marker file, in the current directory or above it.
+fn find_marker() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(MARKER_FILE))
+        .find(|path| path.is_file())
+}
+
+// The config files to read, lowest precedence first: the machine-wide one, the user's (e.g.
+// ~/.config/dark/config.toml), the nearest .dark marker, then the project's dark.toml.
+fn config_paths(include_system: bool) -> Vec<PathBuf> {
+    let mut paths = vec![];
+    if include_system {
+        paths.push(PathBuf::from(SYSTEM_CONFIG_FILE));
+    }
+    if let Some(dir) = dirs::config_dir() {
+        paths.push(dir.join("dark").join("config.toml"));
+    }
+    if let Some(marker) = find_marker() {
+        paths.push(marker);
+    }
+    paths.push(PathBuf::from(CONFIG_FILE));
+    paths
+}
+
+// The config files that exist and so went into the settings, lowest precedence first.
+pub(crate) fn files(include_system: bool) -> Vec<PathBuf> {
+    config_paths(include_system)
+        .into_iter()
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+fn read(path: &Path) -> Result<Option<ConfigFile>, DarkError> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(path)
+        .map_err(|err| DarkError::Config(path.display().to_string(), err.to_string()))?;
+    let parsed = if path.file_name() == Some(MARKER_FILE.as_ref()) {
+        toml::from_str::<Marker>(&contents).map(ConfigFile::from)
+    } else {
+        toml::from_str(&contents)
+    };
+    parsed
+        .map(Some)
+        .map_err(|err| DarkError::Config(path.display().to_string(), err.to_string()))
+}
+
+// Load the config files that exist, each overriding the ones before it, with the named
+// profile's settings merged over the top-level ones.
+pub(crate) fn load(profile: Option<&str>, include_system: bool) -> Result<Settings, DarkError> {
+    load_from(&config_paths(include_system), profile)
+}
+
+// load, from `paths` (lowest precedence first) rather than the usual places.
+fn load_from(paths: &[PathBuf], profile: Option<&str>) -> Result<Settings, DarkError> {
+    let mut config: Option<ConfigFile> = None;
+    for path in paths {
+        if let Some(layer) = read(path)? {
+            config = Some(match config {
+                Some(base) => layer.or(base),
+                None => layer,
+            });
+        }
+    }
+
+    match (config, profile) {
+        (None, None) => Ok(Settings::default()),
+        (None, Some(name)) => Err(DarkError::UnknownProfile(
+            name.to_string(),
+            format!(
+                "there's no {} in this directory, no {} here or above it, and no user or system config file",
+                CONFIG_FILE, MARKER_FILE
+            ),
+        )),
+        (Some(config), None) => Ok(config.defaults),
+        (Some(mut config), Some(name)) => match config.profile.remove(name) {
+            Some(settings) => Ok(settings.or(config.defaults)),
+            None => Err(DarkError::UnknownProfile(
+                name.to_string(),
+                if config.profile.is_empty() {
+                    "no config file defines any profiles".to_string()
+                } else {
+                    format!(
+                        "the config files define: {}",
+                        config
+                            .profile
+                            .keys()
+                            .cloned()
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                },
+            )),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_marker_only_sets_canvas_and_host() {
+        let tmp = tempfile::tempdir().unwrap();
+        let marker = tmp.path().join(MARKER_FILE);
+        fs::write(&marker, "canvas = \"c\"\nhost = \"https://example.com\"\n").unwrap();
+        let settings = read(&marker).unwrap().unwrap().defaults;
+        assert_eq!(settings.canvas.as_ref().map(String::as_str), Some("c"));
+        assert_eq!(
+            settings.host.as_ref().map(String::as_str),
+            Some("https://example.com")
+        );
+
+        fs::write(
+            &marker,
+            "canvas = \"c\"\n[profile.staging]\ncanvas = \"d\"\n",
+        )
+        .unwrap();
+        match read(&marker) {
+            Err(DarkError::Config(path, _)) => assert_eq!(path, marker.display().to_string()),
+            _ => panic!("a .dark with a [profile] table was read"),
+        }
+    }
+
+    // The system, user, marker and project files, in that order, with `contents`.
+    fn layers(tmp: &Path, contents: [&str; 4]) -> Vec<PathBuf> {
+        let project = tmp.join("project");
+        fs::create_dir(&project).unwrap();
+        let paths = vec![
+            tmp.join("system.toml"),
+            tmp.join("user.toml"),
+            project.join(MARKER_FILE),
+            project.join(CONFIG_FILE),
+        ];
+        for (path, contents) in paths.iter().zip(&contents) {
+            fs::write(path, contents).unwrap();
+        }
+        paths
+    }
+
+    #[test]
+    fn each_layer_overrides_the_ones_before_it_and_a_profile_overrides_them_all() {
+        let tmp = tempfile::tempdir().unwrap();
+        let paths = layers(
+            tmp.path(),
+            [
+                "host = \"https://system.example.com\"\ncanvas = \"system\"\ncache = false\n",
+                "host = \"https://user.example.com\"\ncanvas = \"user\"\n",
+                "canvas = \"marker\"\n",
+                "[profile.staging]\ncanvas = \"staging\"\n",
+            ],
+        );
+        let settings = load_from(&paths, None).unwrap();
+        assert_eq!(
+            settings.host.as_ref().map(String::as_str),
+            Some("https://user.example.com")
+        );
+        assert_eq!(settings.canvas.as_ref().map(String::as_str), Some("marker"));
+        assert_eq!(settings.cache, Some(false));
+
+        let staging = load_from(&paths, Some("staging")).unwrap();
+        assert_eq!(staging.canvas.as_ref().map(String::as_str), Some("staging"));
+        assert_eq!(
+            staging.host.as_ref().map(String::as_str),
+            Some("https://user.example.com")
+        );
+
+        // without the marker and project files, the user's file wins
+        let settings = load_from(&paths[..2], None).unwrap();
+        assert_eq!(settings.canvas.as_ref().map(String::as_str), Some("user"));
+    }
+
+    #[test]
+    fn an_unknown_profile_says_which_ones_there_are() {
+        let tmp = tempfile::tempdir().unwrap();
+        let paths = layers(
+            tmp.path(),
+            ["", "[profile.dev]\n", "", "[profile.staging]\n"],
+        );
+        match load_from(&paths, Some("prod")) {
+            Err(DarkError::UnknownProfile(name, detail)) => {
+                assert_eq!(name, "prod");
+                assert_eq!(detail, "the config files define: dev, staging");
+            }
+            _ => panic!("prod was found"),
+        }
+        match load_from(&[tmp.path().join("missing.toml")], Some("prod")) {
+            Err(DarkError::UnknownProfile(_, detail)) => assert!(detail.contains(MARKER_FILE)),
+            _ => panic!("prod was found"),
+        }
+    }
+
+    #[test]
+    fn host_credentials_take_a_user_and_password_but_not_a_token() {
+        let tmp = tempfile::tempdir().unwrap();
+        let paths = layers(
+            tmp.path(),
+            [
+                "[hosts.\"https://a.example.com/\"]\nuser = \"me\"\n",
+                "[hosts.\"https://a.example.com/\"]\npassword = \"pw\"\n[hosts.\"https://b.example.com\"]\ntoken = \"t\"\n",
+                "",
+                "",
+            ],
+        );
+        let settings = load_from(&paths, None).unwrap();
+        assert_eq!(
+            settings.credentials("https://a.example.com").unwrap(),
+            Some(("me".to_string(), "pw".to_string()))
+        );
+        match settings.credentials("https://b.example.com") {
+            Err(DarkError::HostCredentials(url, detail)) => {
+                assert_eq!(url, "https://b.example.com");
+                assert!(detail.contains("username and password"));
+            }
+            _ => panic!("a token was accepted"),
+        }
+        assert_eq!(settings.credentials("https://c.example.com").unwrap(), None);
+    }
+}
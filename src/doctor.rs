@@ -0,0 +1,207 @@
+// `dark doctor`: check, one step at a time, what an upload depends on (the host resolving, a
+// connection and TLS handshake, the login endpoint answering, the credentials working, the
+// session cache being writable) and print what passed, what failed and what to try. Nothing is
+// uploaded, and a successful login isn't cached.
+
+use std::net::ToSocketAddrs;
+
+use reqwest::header::{HeaderMap, USER_AGENT};
+
+use {cache, connection_error, cookie_and_csrf, DarkError, Verbosity, PKG_NAME, VERSION};
+
+enum Outcome {
+    Passed(String),
+    // what went wrong, and what to try
+    Failed(String, &'static str),
+    // why the check wasn't run
+    Skipped(String),
+}
+
+struct Check {
+    name: &'static str,
+    outcome: Outcome,
+}
+
+impl Check {
+    fn passed(&self) -> bool {
+        match self.outcome {
+            Outcome::Passed(_) => true,
+            _ => false,
+        }
+    }
+
+    fn print(&self) {
+        match self.outcome {
+            Outcome::Passed(ref detail) => println!("ok    {:<12} {}", self.name, detail),
+            Outcome::Failed(ref detail, hint) => {
+                println!("FAIL  {:<12} {}", self.name, detail);
+                println!("      {:<12} Try: {}", "", hint);
+            }
+            Outcome::Skipped(ref why) => println!("skip  {:<12} {}", self.name, why),
+        }
+    }
+}
+
+const CREDENTIALS_HINT: &str = "set --user and --password, $DARK_CLI_USER and $DARK_CLI_PASSWORD, a [hosts.\"<host>\"] table in a config file, or a netrc entry (see dark --help)";
+
+// Whether the host's name resolves to an address.
+fn check_resolves(host: &str) -> Check {
+    let url = match reqwest::Url::parse(host) {
+        Ok(url) => url,
+        Err(err) => {
+            return Check {
+                name: "host",
+                outcome: Outcome::Failed(
+                    format!("{} isn't a URL ({})", host, err),
+                    "pass the host with its scheme, e.g. --host https://darklang.com",
+                ),
+            }
+        }
+    };
+    let name = url.host_str().unwrap_or_default().to_string();
+    let port = url.port_or_known_default().unwrap_or(443);
+    let outcome = match (name.as_str(), port).to_socket_addrs() {
+        Ok(mut addrs) => match addrs.next() {
+            Some(addr) => Outcome::Passed(format!("{} resolves to {}", name, addr.ip())),
+            None => Outcome::Failed(
+                format!("{} doesn't resolve to any address", name),
+                "check the host's spelling, and your DNS settings",
+            ),
+        },
+        Err(err) => Outcome::Failed(
+            format!("{} doesn't resolve ({})", name, err),
+            "check the host's spelling (--host, --dev, $DARK_HOST or the config files), and that this machine is online",
+        ),
+    };
+    Check {
+        name: "host",
+        outcome,
+    }
+}
+
+// Whether `url` answers at all: any HTTP response, even an error status, means the connection
+// (and, for https, the TLS handshake) worked.
+fn check_reachable(name: &'static str, url: &str) -> Check {
+    let resp = reqwest::Client::new()
+        .get(url)
+        .header(USER_AGENT, format!("{}/{}", PKG_NAME, VERSION))
+        .send();
+    let outcome = match resp {
+        Ok(resp) if url.starts_with("https://") => Outcome::Passed(format!(
+            "{} responded {}; the TLS certificate is valid",
+            url,
+            resp.status()
+        )),
+        Ok(resp) => Outcome::Passed(format!("{} responded {}", url, resp.status())),
+        Err(error) => match connection_error(url, &error) {
+            err @ DarkError::Tls(_, _) => Outcome::Failed(
+                err.to_string(),
+                "if a proxy or antivirus intercepts HTTPS here, make sure this machine trusts its certificate; otherwise check the clock is right",
+            ),
+            err => Outcome::Failed(
+                err.to_string(),
+                "check your network, any proxy settings ($HTTPS_PROXY), and that the host is up (dark ping)",
+            ),
+        },
+    };
+    Check { name, outcome }
+}
+
+// Whether the credentials an upload would use log in at `login_url`.
+fn check_credentials(
+    login_url: &str,
+    credentials: Result<Option<(String, String, String)>, DarkError>,
+) -> Check {
+    let outcome = match credentials {
+        Err(err) => Outcome::Failed(err.to_string(), CREDENTIALS_HINT),
+        Ok(None) => Outcome::Skipped(format!("no credentials found; {}", CREDENTIALS_HINT)),
+        Ok(Some((user, password, source))) => {
+            match cookie_and_csrf(
+                login_url,
+                user.clone(),
+                password,
+                Verbosity::Quiet,
+                &HeaderMap::new(),
+            ) {
+                Ok(_) => Outcome::Passed(format!(
+                    "logged in as {} (credentials from {})",
+                    user, source
+                )),
+                Err(err) => Outcome::Failed(
+                    format!(
+                        "logging in as {} (credentials from {}) failed: {}",
+                        user, source, err
+                    ),
+                    "check the username and password, and whether the account can log in at all",
+                ),
+            }
+        }
+    };
+    Check {
+        name: "credentials",
+        outcome,
+    }
+}
+
+fn check_cache() -> Check {
+    Check {
+        name: "cache",
+        outcome: match cache::check_writable() {
+            Ok(dir) => Outcome::Passed(format!("{} is writable", dir.display())),
+            Err(err) => Outcome::Failed(
+                err,
+                "set $XDG_CACHE_HOME to a directory you can write to, or upload with --no-cache",
+            ),
+        },
+    }
+}
+
+// Run every check against `host` (logging in at `login_url`) with the `credentials` an upload
+// would use, printing each as it finishes. The network checks are skipped once one they depend on
+// has failed.
+pub(crate) fn run(
+    host: &str,
+    login_url: &str,
+    credentials: Result<Option<(String, String, String)>, DarkError>,
+) -> Result<(), DarkError> {
+    let skipped = |name, why: &str| Check {
+        name,
+        outcome: Outcome::Skipped(why.to_string()),
+    };
+    let mut checks = vec![];
+    let mut run = |check: Check| {
+        check.print();
+        let passed = check.passed();
+        checks.push(check);
+        passed
+    };
+
+    let resolved = run(check_resolves(host));
+    if resolved {
+        run(check_reachable("connect", host));
+    } else {
+        run(skipped("connect", "the host didn't resolve"));
+    }
+    if run(check_reachable("login", login_url)) {
+        run(check_credentials(login_url, credentials));
+    } else {
+        run(skipped(
+            "credentials",
+            "the login endpoint couldn't be reached",
+        ));
+    }
+    run(check_cache());
+
+    let failed = checks
+        .iter()
+        .filter(|check| match check.outcome {
+            Outcome::Failed(_, _) => true,
+            _ => false,
+        })
+        .count();
+    if failed > 0 {
+        return Err(DarkError::DoctorFailed(failed, checks.len()));
+    }
+    println!("\nEverything an upload to {} needs checks out.", host);
+    Ok(())
+}
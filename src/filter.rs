@@ -0,0 +1,354 @@
+use std::borrow::Cow;
+
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+
+use DarkError;
+
+// Whether globs ignore case unless --ignore-case or --case-sensitive-globs says otherwise: they do
+// on macOS and Windows, whose filesystems usually don't tell JS from js either, and don't
+// elsewhere.
+pub(crate) const IGNORE_CASE_BY_DEFAULT: bool = cfg!(any(target_os = "macos", windows));
+
+// Whether a backslash in a path argument or pattern separates directories, as it does on Windows,
+// rather than escaping the character after it.
+const BACKSLASH_SEPARATES: bool = cfg!(windows);
+
+fn is_separator(c: char, backslash_separates: bool) -> bool {
+    c == '/' || (backslash_separates && c == '\\')
+}
+
+// `pattern` with forward slashes between its directories, as asset names have.
+fn forward_slashes(pattern: &str, backslash_separates: bool) -> Cow<str> {
+    if backslash_separates {
+        Cow::Owned(pattern.replace('\\', "/"))
+    } else {
+        Cow::Borrowed(pattern)
+    }
+}
+
+// A file the walk found but left out of the upload, which rule left it out, and why.
+pub(crate) struct Excluded {
+    pub path: String,
+    pub rule: &'static str,
+    pub reason: String,
+}
+
+// The rules deciding which of the files under dir get uploaded.
+pub(crate) struct Filters {
+    exclude: GlobSet,
+    // where each exclude pattern came from, for the reason a file was left out (e.g.
+    // "--exclude *.map", or "*.map in ignore.txt")
+    exclude_sources: Vec<String>,
+    // files smaller than this many bytes are left out; 0 keeps everything
+    min_size: u64,
+    skip_empty: bool,
+}
+
+impl Filters {
+    // Build the filters from the --exclude patterns and those in the --exclude-from files, which
+    // are globs matched against each file's asset name (its path under dir, with forward
+    // slashes), and --min-file-size/--skip-empty.
+    pub(crate) fn new(
+        exclude: &[&str],
+        exclude_from: &[&str],
+        min_size: u64,
+        skip_empty: bool,
+        ignore_case: bool,
+    ) -> Result<Filters, DarkError> {
+        let mut patterns: Vec<(String, String)> = exclude
+            .iter()
+            .map(|&pattern| (pattern.to_string(), format!("--exclude {}", pattern)))
+            .collect();
+        for &path in exclude_from {
+            for pattern in read_patterns(path)? {
+                let source = format!("{} in {}", pattern, path);
+                patterns.push((pattern, source));
+            }
+        }
+
+        let mut builder = GlobSetBuilder::new();
+        for (pattern, source) in &patterns {
+            let glob = exclude_glob(pattern, ignore_case).map_err(|err| {
+                DarkError::InvalidArgument("exclude".to_string(), format!("{}: {}", source, err))
+            })?;
+            builder.add(glob);
+        }
+        Ok(Filters {
+            exclude: builder.build().map_err(|err| {
+                DarkError::InvalidArgument("exclude".to_string(), format!("{}", err))
+            })?,
+            exclude_sources: patterns.into_iter().map(|(_, source)| source).collect(),
+            min_size,
+            skip_empty,
+        })
+    }
+
+    // Whether (and why) the file named `name`, `size` bytes long, should be left out; None if it
+    // should be uploaded.
+    pub(crate) fn exclusion(&self, name: &str, size: u64) -> Option<Excluded> {
+        let excluded = |rule, reason| {
+            Some(Excluded {
+                path: name.to_string(),
+                rule,
+                reason,
+            })
+        };
+        if let Some(&index) = self.exclude.matches(name).first() {
+            return excluded(
+                "exclude",
+                format!("matched {}", self.exclude_sources[index]),
+            );
+        }
+        if size == 0 && self.skip_empty {
+            return excluded("skip-empty", "empty".to_string());
+        }
+        if size < self.min_size {
+            return excluded(
+                "min-file-size",
+                format!("{} bytes, under --min-file-size {}", size, self.min_size),
+            );
+        }
+        None
+    }
+}
+
+// An --exclude pattern, matched against the whole asset name. Like gitignore's patterns with a
+// slash in them (and unlike a shell's), * and ? match / too, so *.map catches js/app.js.map.
+fn exclude_glob(pattern: &str, ignore_case: bool) -> Result<Glob, globset::Error> {
+    GlobBuilder::new(&forward_slashes(pattern, BACKSLASH_SEPARATES))
+        .case_insensitive(ignore_case)
+        .build()
+}
+
+// The glob part of a path argument, matched against the path under the dir being walked. Like a
+// shell, * doesn't cross directories; ** does.
+fn path_glob(pattern: &str, ignore_case: bool) -> Result<Glob, globset::Error> {
+    GlobBuilder::new(&forward_slashes(pattern, BACKSLASH_SEPARATES))
+        .literal_separator(true)
+        .case_insensitive(ignore_case)
+        .build()
+}
+
+// Whether `pattern` matches `path` as an --exclude pattern, and as the glob part of a path
+// argument, for `dark help-patterns --test-pattern`, ignoring case as globs do by default here.
+pub(crate) fn test_pattern(pattern: &str, path: &str) -> Result<(bool, bool), DarkError> {
+    let invalid = |err: globset::Error| {
+        DarkError::InvalidArgument("test-pattern".to_string(), format!("{}", err))
+    };
+    let path = path.trim_start_matches("./");
+    Ok((
+        exclude_glob(pattern, IGNORE_CASE_BY_DEFAULT)
+            .map_err(invalid)?
+            .compile_matcher()
+            .is_match(path),
+        path_glob(pattern, IGNORE_CASE_BY_DEFAULT)
+            .map_err(invalid)?
+            .compile_matcher()
+            .is_match(path),
+    ))
+}
+
+// The patterns in an --exclude-from file: one glob per line, like rsync's and tar's, skipping
+// blank lines and # comments.
+fn read_patterns(path: &str) -> Result<Vec<String>, DarkError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|error| DarkError::FileRead(path.to_string(), error))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+// Whether `component` of a path has glob characters in it.
+fn is_glob(component: &str) -> bool {
+    component.contains(|c| c == '*' || c == '?' || c == '[' || c == '{')
+}
+
+// A path argument split into the directory to walk and, if the path has glob characters, the
+// pattern (relative to that directory) files under it must match: dist/**/*.css walks dist for
+// **/*.css, and *.css walks . for *.css. With `backslash_separates`, dist\**\*.css does too.
+fn split_glob(path: &str, backslash_separates: bool) -> (&str, Option<&str>) {
+    let mut offset = 0;
+    for component in path.split(|c| is_separator(c, backslash_separates)) {
+        if is_glob(component) {
+            let root = path[..offset].trim_end_matches(|c| is_separator(c, backslash_separates));
+            return (
+                if root.is_empty() { "." } else { root },
+                Some(&path[offset..]),
+            );
+        }
+        offset += component.len() + 1;
+    }
+    (path, None)
+}
+
+// The dirs to walk for the path arguments, and which files under each one were asked for. Most
+// arguments are plain directories, which take every file in them, but a shell that doesn't
+// expand globs (or a quoted one) passes patterns like 'dist/**/*.css' through as is.
+pub(crate) struct Paths<'a> {
+    pub(crate) dirs: Vec<&'a str>,
+    // the patterns (and their path arguments) under each dir that was only globbed; a dir that
+    // was also given plainly isn't here, since it takes everything
+    globs: Vec<(&'a str, Vec<&'a str>, GlobSet)>,
+    // --only: the one glob every file, under whichever dir, has to match
+    only: Option<(&'a str, GlobMatcher)>,
+}
+
+impl<'a> Paths<'a> {
+    pub(crate) fn new(
+        paths: &[&'a str],
+        only: Option<&'a str>,
+        ignore_case: bool,
+    ) -> Result<Paths<'a>, DarkError> {
+        let mut dirs: Vec<&str> = vec![];
+        let mut plain: Vec<&str> = vec![];
+        let mut patterns: Vec<(&str, &str, &str)> = vec![];
+        for &path in paths {
+            let (dir, pattern) = split_glob(path, BACKSLASH_SEPARATES);
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+            match pattern {
+                Some(pattern) => patterns.push((dir, path, pattern)),
+                None => plain.push(dir),
+            }
+        }
+
+        let mut globs = vec![];
+        for &dir in &dirs {
+            if plain.contains(&dir) {
+                continue;
+            }
+            let mut builder = GlobSetBuilder::new();
+            let mut args = vec![];
+            for &(_, path, pattern) in patterns.iter().filter(|&&(glob_dir, _, _)| glob_dir == dir)
+            {
+                let glob = path_glob(pattern, ignore_case).map_err(|err| {
+                    DarkError::InvalidArgument("dir".to_string(), format!("{}", err))
+                })?;
+                builder.add(glob);
+                args.push(path);
+            }
+            let set = builder
+                .build()
+                .map_err(|err| DarkError::InvalidArgument("dir".to_string(), format!("{}", err)))?;
+            globs.push((dir, args, set));
+        }
+        let only = match only {
+            Some(pattern) => {
+                let glob =
+                    path_glob(pattern.trim_start_matches("./"), ignore_case).map_err(|err| {
+                        DarkError::InvalidArgument("only".to_string(), format!("{}", err))
+                    })?;
+                Some((pattern, glob.compile_matcher()))
+            }
+            None => None,
+        };
+        Ok(Paths { dirs, globs, only })
+    }
+
+    // Whether the file named `name` under `dir` was asked for, and if it was by glob, the path
+    // arguments that matched it.
+    pub(crate) fn matches(&self, dir: &str, name: &str) -> Option<Vec<&'a str>> {
+        let mut matched = self.matches_paths(dir, name)?;
+        if let Some((pattern, ref matcher)) = self.only {
+            if !matcher.is_match(name) {
+                return None;
+            }
+            matched.push(pattern);
+        }
+        Some(matched)
+    }
+
+    fn matches_paths(&self, dir: &str, name: &str) -> Option<Vec<&'a str>> {
+        match self.globs.iter().find(|&&(glob_dir, _, _)| glob_dir == dir) {
+            Some(&(_, ref args, ref set)) => {
+                let matched: Vec<&str> = set
+                    .matches(name)
+                    .into_iter()
+                    .map(|index| args[index])
+                    .collect();
+                if matched.is_empty() {
+                    None
+                } else {
+                    Some(matched)
+                }
+            }
+            None => Some(vec![]),
+        }
+    }
+
+    // Every path argument that was a glob, and the --only glob.
+    pub(crate) fn globs(&self) -> Vec<&'a str> {
+        self.globs
+            .iter()
+            .flat_map(|&(_, ref args, _)| args.iter().cloned())
+            .chain(self.only.as_ref().map(|&(pattern, _)| pattern))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excludes_match_across_directories_and_fold_case_when_asked() {
+        let filters = |ignore_case| Filters::new(&["*.MAP"], &[], 0, false, ignore_case).unwrap();
+        assert!(filters(true).exclusion("js/app.js.map", 1).is_some());
+        assert!(filters(false).exclusion("js/app.js.map", 1).is_none());
+        assert_eq!(
+            filters(true).exclusion("app.js.MAP", 1).unwrap().reason,
+            "matched --exclude *.MAP"
+        );
+    }
+
+    #[test]
+    fn path_globs_are_anchored_to_their_directory() {
+        let paths = Paths::new(&["dist/*.css", "dist/**/*.png"], None, false).unwrap();
+        assert_eq!(paths.dirs, vec!["dist"]);
+        assert_eq!(paths.matches("dist", "app.css"), Some(vec!["dist/*.css"]));
+        assert_eq!(paths.matches("dist", "sub/app.css"), None);
+        assert_eq!(
+            paths.matches("dist", "img/a/b.png"),
+            Some(vec!["dist/**/*.png"])
+        );
+        assert_eq!(paths.matches("dist", "b.png"), Some(vec!["dist/**/*.png"]));
+
+        // a dir that's also given plainly takes everything in it
+        let paths = Paths::new(&["dist/*.css", "dist"], None, false).unwrap();
+        assert_eq!(paths.matches("dist", "app.js"), Some(vec![]));
+    }
+
+    #[test]
+    fn only_has_to_match_as_well_as_the_path_arguments() {
+        let paths = Paths::new(&["dist", "public/*.css"], Some("./*.css"), true).unwrap();
+        assert_eq!(paths.matches("dist", "APP.CSS"), Some(vec!["./*.css"]));
+        assert_eq!(paths.matches("dist", "app.js"), None);
+        assert_eq!(paths.matches("dist", "sub/app.css"), None);
+        assert_eq!(
+            paths.matches("public", "app.css"),
+            Some(vec!["public/*.css", "./*.css"])
+        );
+        assert_eq!(paths.globs(), vec!["public/*.css", "./*.css"]);
+    }
+
+    #[test]
+    fn backslashes_separate_directories_only_where_they_do_on_windows() {
+        assert_eq!(
+            split_glob("dist\\css\\**\\*.css", true),
+            ("dist\\css", Some("**\\*.css"))
+        );
+        assert_eq!(forward_slashes("**\\*.css", true), "**/*.css");
+        // elsewhere, a backslash escapes the character after it, as in a shell
+        assert_eq!(
+            split_glob("dist\\css\\**\\*.css", false),
+            (".", Some("dist\\css\\**\\*.css"))
+        );
+        assert_eq!(forward_slashes("\\*.css", false), "\\*.css");
+        assert_eq!(split_glob("dist/", true), ("dist/", None));
+        assert_eq!(split_glob("*.css", false), (".", Some("*.css")));
+    }
+}
@@ -0,0 +1,103 @@
+use std::path::Path;
+use std::process::Command;
+
+use DarkError;
+
+// Run git with the given args and return its trimmed stdout, or None if git isn't installed or
+// the command failed (e.g. because we're not in a git repo).
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn branch() -> Result<String, DarkError> {
+    match git(&["rev-parse", "--abbrev-ref", "HEAD"]) {
+        // rev-parse answers "HEAD" when there's no branch checked out
+        Some(ref branch) if branch == "HEAD" => Err(DarkError::CanvasTemplate(
+            "can't resolve {branch}: HEAD is detached".to_string(),
+        )),
+        // canvas names can't contain slashes, so feature/foo becomes feature-foo
+        Some(branch) => Ok(branch.replace('/', "-")),
+        None => Err(DarkError::CanvasTemplate(
+            "can't resolve {branch}: not in a git repository".to_string(),
+        )),
+    }
+}
+
+fn repo() -> Result<String, DarkError> {
+    git(&["rev-parse", "--show-toplevel"])
+        .and_then(|toplevel| {
+            Path::new(&toplevel)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .ok_or_else(|| {
+            DarkError::CanvasTemplate("can't resolve {repo}: not in a git repository".to_string())
+        })
+}
+
+// Fill in the {branch} and {repo} placeholders in a --canvas-template, e.g. "myapp-{branch}"
+// becomes "myapp-staging" on the staging branch.
+pub(crate) fn canvas_from_template(template: &str) -> Result<String, DarkError> {
+    let mut canvas = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        canvas.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| {
+            DarkError::CanvasTemplate(format!("unclosed placeholder in {}", template))
+        })? + start;
+        match &rest[start + 1..end] {
+            "branch" => canvas.push_str(&branch()?),
+            "repo" => canvas.push_str(&repo()?),
+            other => {
+                return Err(DarkError::CanvasTemplate(format!(
+                    "unknown placeholder {{{}}} (expected {{branch}} or {{repo}})",
+                    other
+                )))
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    canvas.push_str(rest);
+    Ok(canvas)
+}
+
+// For --require-clean-git: the uncommitted changes (as `git status --porcelain` lines, e.g.
+// " M src/app.js") in each git repository holding one of `dirs`, by the repository's top level.
+// Every dir has to be in one, or there's nothing to check.
+pub(crate) fn uncommitted_changes(dirs: &[&str]) -> Result<Vec<(String, Vec<String>)>, DarkError> {
+    let mut toplevels: Vec<String> = vec![];
+    let mut dirty = vec![];
+    for &dir in dirs {
+        let not_a_repo = || {
+            DarkError::InvalidArgument(
+                "require-clean-git".to_string(),
+                format!("{} isn't in a git repository", dir),
+            )
+        };
+        let toplevel = git(&["-C", dir, "rev-parse", "--show-toplevel"]).ok_or_else(not_a_repo)?;
+        if toplevels.contains(&toplevel) {
+            continue;
+        }
+        // not git(), whose trim() would take the first line's leading status column with it
+        let status = Command::new("git")
+            .args(&["-C", &toplevel, "status", "--porcelain"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .ok_or_else(not_a_repo)?;
+        let changes: Vec<String> = String::from_utf8_lossy(&status.stdout)
+            .lines()
+            .map(str::to_string)
+            .collect();
+        if !changes.is_empty() {
+            dirty.push((toplevel.clone(), changes));
+        }
+        toplevels.push(toplevel);
+    }
+    Ok(dirty)
+}
@@ -0,0 +1,140 @@
+// --hash-algorithm: the digest used everywhere a file is hashed (change detection, --name-from-hash,
+// --checksums, --lockfile and verification). SHA-256 unless asked otherwise; BLAKE3 is much faster
+// on large trees, and SHA-1 is there for servers and tools that only speak it.
+
+use std::io::{self, Read, Write};
+
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum HashAlgorithm {
+    Sha256,
+    Sha1,
+    Blake3,
+}
+
+// The values --hash-algorithm accepts, the default first.
+pub(crate) const NAMES: &[&str] = &["sha256", "sha1", "blake3"];
+
+impl HashAlgorithm {
+    pub(crate) fn from_name(name: &str) -> Option<HashAlgorithm> {
+        match name {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "sha1" => Some(HashAlgorithm::Sha1),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    // The hex digest of `bytes`.
+    pub(crate) fn hex(self, bytes: &[u8]) -> String {
+        match self {
+            HashAlgorithm::Sha256 => format!("{:x}", Sha256::digest(bytes)),
+            HashAlgorithm::Sha1 => format!("{:x}", Sha1::digest(bytes)),
+            HashAlgorithm::Blake3 => blake3::hash(bytes).to_hex().to_string(),
+        }
+    }
+
+    // The hex digest of everything `reader` has left, read a buffer at a time.
+    pub(crate) fn hex_reader<R: Read>(self, reader: &mut R) -> io::Result<String> {
+        let mut hasher = Hasher::new(self);
+        io::copy(reader, &mut hasher)?;
+        Ok(hasher.hex())
+    }
+}
+
+// A digest being worked out a piece at a time, for bytes that are being read for something else
+// anyway (like the upload's body).
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Sha1(Sha1),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub(crate) fn new(algorithm: HashAlgorithm) -> Hasher {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.input(bytes),
+            Hasher::Sha1(hasher) => hasher.input(bytes),
+            Hasher::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+        }
+    }
+
+    // The hex digest of everything passed to update.
+    pub(crate) fn hex(self) -> String {
+        let bytes = match self {
+            Hasher::Sha256(hasher) => hasher.result().to_vec(),
+            Hasher::Sha1(hasher) => hasher.result().to_vec(),
+            Hasher::Blake3(hasher) => return hasher.finalize().to_hex().to_string(),
+        };
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.update(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests_match_known_values_whether_hashed_at_once_or_streamed() {
+        let known = [
+            (
+                HashAlgorithm::Sha256,
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                HashAlgorithm::Sha1,
+                "a9993e364706816aba3e25717850c26c9cd0d89d",
+            ),
+            (
+                HashAlgorithm::Blake3,
+                "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+            ),
+        ];
+        for &(algorithm, digest) in &known {
+            assert_eq!(algorithm.hex(b"abc"), digest, "{}", algorithm.name());
+            assert_eq!(
+                algorithm.hex_reader(&mut &b"abc"[..]).unwrap(),
+                digest,
+                "{}",
+                algorithm.name()
+            );
+            let mut hasher = Hasher::new(algorithm);
+            hasher.update(b"a");
+            hasher.update(b"bc");
+            assert_eq!(hasher.hex(), digest, "{}", algorithm.name());
+            assert_eq!(HashAlgorithm::from_name(algorithm.name()), Some(algorithm));
+        }
+        assert_eq!(HashAlgorithm::from_name("md5"), None);
+    }
+}
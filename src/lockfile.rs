@@ -0,0 +1,85 @@
+// --lockfile: after a successful deploy, record each asset's hash in .dark-assets.json, which is
+// meant to be committed alongside the site. The next run compares what it's about to upload
+// against it, so CI can check (with --frozen) that what's committed is what was deployed.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use {write_atomically, DarkError, HashAlgorithm};
+
+pub(crate) const PATH: &str = ".dark-assets.json";
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct Lockfile {
+    // the --hash-algorithm the hashes were made with; they can't be compared with any other's
+    pub(crate) hash_algorithm: String,
+    // asset name -> hash
+    pub(crate) files: BTreeMap<String, String>,
+}
+
+// The lockfile; None if there isn't one yet.
+pub(crate) fn read(path: &str) -> Result<Option<Lockfile>, DarkError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(DarkError::FileRead(path.to_string(), err)),
+    };
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|err| DarkError::Config(path.to_string(), err.to_string()))
+}
+
+// How the files about to be uploaded (`hashes`, asset name -> hash) differ from `locked`, one
+// line per asset.
+pub(crate) fn drift(
+    path: &str,
+    locked: &BTreeMap<String, String>,
+    hashes: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut drift = vec![];
+    for (name, hash) in hashes {
+        match locked.get(name) {
+            Some(locked_hash) if locked_hash == hash => (),
+            Some(_) => drift.push(format!("{} has changed since {} was written.", name, path)),
+            None => drift.push(format!("{} isn't in {}.", name, path)),
+        }
+    }
+    for name in locked.keys().filter(|&name| !hashes.contains_key(name)) {
+        drift.push(format!("{} is in {}, but wasn't found.", name, path));
+    }
+    drift
+}
+
+// Write `hashes`, made with `algorithm`, to the lockfile. With `merge`, assets already in it are
+// kept (for --watch, which only uploads what changed), unless their hashes were made with another
+// algorithm.
+pub(crate) fn write(
+    path: &str,
+    algorithm: HashAlgorithm,
+    hashes: &BTreeMap<String, String>,
+    merge: bool,
+) -> Result<(), DarkError> {
+    let mut files = if merge {
+        read(path)?
+            .filter(|locked| locked.hash_algorithm == algorithm.name())
+            .map(|locked| locked.files)
+            .unwrap_or_default()
+    } else {
+        BTreeMap::new()
+    };
+    files.extend(
+        hashes
+            .iter()
+            .map(|(name, hash)| (name.clone(), hash.clone())),
+    );
+    let locked = Lockfile {
+        hash_algorithm: algorithm.name().to_string(),
+        files,
+    };
+    let mut contents = serde_json::to_string_pretty(&locked).unwrap_or_default();
+    // it's committed, so end it like any other text file
+    contents.push('\n');
+    write_atomically(Path::new(path), contents.as_bytes())
+}
@@ -1,30 +1,71 @@
+extern crate atty;
+extern crate blake3;
 extern crate clap;
+extern crate ctrlc;
+extern crate flate2;
+extern crate globset;
 extern crate http;
 extern crate humansize;
+extern crate hyper;
+extern crate mime_guess;
 extern crate netrc;
+extern crate notify;
 extern crate path_slash;
 extern crate reqwest;
 extern crate serde;
 extern crate serde_json;
+extern crate serde_yaml;
+extern crate sha1;
+extern crate sha2;
+extern crate toml;
 extern crate walkdir; // could probs replace this with std::fs
 
 #[macro_use]
 extern crate failure;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use flate2::read::GzEncoder;
+use flate2::Compression;
 use humansize::{file_size_opts as options, FileSize};
-use reqwest::header::{CONTENT_TYPE, USER_AGENT};
-use reqwest::{multipart, StatusCode};
+use reqwest::header::{
+    HeaderMap, HeaderName, CONTENT_ENCODING, CONTENT_TYPE, LOCATION, SERVER, USER_AGENT,
+    WWW_AUTHENTICATE,
+};
+use reqwest::{RedirectPolicy, StatusCode};
 use walkdir::WalkDir;
 
 use http::Uri;
 use netrc::Netrc;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
+use std::fmt;
 use std::fs::File;
-use std::io::BufReader;
-use std::path::Path;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+
+use hash::HashAlgorithm;
+
+mod cache;
+mod cache_control;
+mod config;
+mod doctor;
+mod filter;
+mod git;
+mod hash;
+mod lockfile;
+mod multipart;
+mod normalize;
+mod report;
+mod retry;
+mod secrets;
+mod spinner;
+mod state;
+mod warning;
 
 #[derive(Debug, Fail)]
 enum DarkError {
@@ -33,15 +74,110 @@ enum DarkError {
         _0
     )]
     Auth(u16),
+    #[fail(
+        display = "Failed to authenticate with Dark: the server rejected your username or password (Received 401 from server{}).\nIf you're sure your password is correct, and you used --password, try wrapping your password in single-quotes ('') rather than double-quotes (\"\") to avoid your shell interpreting it.",
+        _0
+    )]
+    AuthUnauthorized(String),
+    #[fail(
+        display = "Your credentials are valid, but you don't have access to {} (Received 403 from server).\nCheck the canvas name, and that your account has been given access to it.",
+        _0
+    )]
+    Forbidden(String),
+    #[fail(
+        display = "The server rejected the upload's CSRF token, even after logging in again:\n\t{}",
+        _0
+    )]
+    CsrfRejected(String),
+    #[fail(
+        display = "Failed to authenticate with Dark: the login endpoint redirected ({}) to {}.\nThis usually means your Dark instance has moved or requires signing in another way (e.g. SSO); only a redirect to the same scheme, host and port is followed, so your password never goes over plain HTTP or to another server. If it moved, update the host you're deploying to, or point --login-url (or login_url in its [hosts.\"<url>\"] table) at where it logs in now.",
+        _0, _1
+    )]
+    AuthRedirect(u16, String),
+    #[fail(
+        display = "Failed to authenticate with Dark: --auth-command {} {}.\nIt should print the session as JSON, like the login endpoint does: {{\"sessionKey\": \"...\", \"csrfToken\": \"...\"}}",
+        _0, _1
+    )]
+    AuthCommand(String, String),
+    #[fail(
+        display = "Failed to authenticate with Dark: the server sent back a login page instead of a session, which usually means your username or password is wrong.\nIf you're sure your password is correct, and you used --password, try wrapping your password in single-quotes ('') rather than double-quotes (\"\") to avoid your shell interpreting it."
+    )]
+    AuthLoginPage,
+    #[fail(
+        display = "Failed to authenticate with Dark: {} responded, but not with a session ({}). Check that it's a Dark instance's login endpoint (see --login-url).",
+        _0, _1
+    )]
+    AuthResponse(String, String),
     #[fail(
         display = "Upload error:\n\tStatus: {}\n\tExecution ID: {}\n\n\t{}",
         _1, _2, _0
     )]
     Non200Response(String, u16, String),
-    #[fail(display = "No files found in {}.", _0)]
-    NoFilesFound(String),
+    #[fail(
+        display = "{} doesn't support {} (Received 404 from server): it may be an older Dark instance than the feature, or not a Dark instance at all. Check the host you're deploying to, or ask whoever runs it to upgrade.",
+        host, feature
+    )]
+    Unsupported { feature: &'static str, host: String },
+    // the dirs, and (if the walk found any files) what left them all out
+    #[fail(display = "No files found in {}{}.", _0, _1)]
+    NoFilesFound(String, String),
+    #[fail(
+        display = "{} didn't match any files. (Pass --allow-empty to upload the rest anyway.)",
+        _0
+    )]
+    GlobMatchedNothing(String),
     #[fail(display = "Upload failure")]
     Upload(#[cause] reqwest::Error),
+    #[fail(display = "Failed to read {}: {}", _0, _1)]
+    FileRead(String, #[cause] std::io::Error),
+    #[fail(
+        display = "{} changed while uploading: it was {} bytes when the files were gathered, and is {} now. Nothing was uploaded from it; run dark again once it's done changing.",
+        _0, _1, _2
+    )]
+    FileChanged(String, u64, u64),
+    #[fail(display = "Couldn't read config file {}: {}", _0, _1)]
+    Config(String, String),
+    #[fail(display = "Invalid [hosts.\"{}\"] in the config files: {}.", _0, _1)]
+    HostCredentials(String, String),
+    #[fail(display = "Unknown profile {}: {}", _0, _1)]
+    UnknownProfile(String, String),
+    #[fail(display = "Invalid --canvas-template: {}", _0)]
+    CanvasTemplate(String),
+    #[fail(display = "Invalid --{}: {}", _0, _1)]
+    InvalidArgument(String, String),
+    #[fail(display = "Both {} and {} would be uploaded as {}.", _1, _2, _0)]
+    DuplicateAsset(String, String, String),
+    #[fail(
+        display = "Not uploading: {} warning(s), and --warnings-as-errors is set.",
+        _0
+    )]
+    WarningsAsErrors(usize),
+    #[fail(
+        display = "The upload went through, but produced {} warning(s), and --warnings-as-errors is set.",
+        _0
+    )]
+    UploadWarnings(usize),
+    #[fail(display = "{} asset(s) differ from {}, and --frozen is set.", _1, _0)]
+    LockfileDrift(String, usize),
+    #[fail(
+        display = "--frozen is set, but there's no {} to check against; deploy once with --lockfile to write it.",
+        _0
+    )]
+    MissingLockfile(String),
+    #[fail(
+        display = "{} records {} hashes, but this run makes {} hashes, and --frozen is set; pass --hash-algorithm {}, or deploy once without --frozen to rewrite it.",
+        _0, _1, _2, _1
+    )]
+    LockfileHashAlgorithm(String, String, String),
+    #[fail(
+        display = "Not uploading: there are uncommitted changes, and --require-clean-git is set:\n{}",
+        _0
+    )]
+    UncommittedChanges(String),
+    #[fail(display = "No credentials set for {}.", _0)]
+    NoCredentials(String),
+    #[fail(display = "Upload cancelled.")]
+    Cancelled,
     #[fail(display = "Missing argument: {}", _0)]
     MissingArgument(String),
     #[fail(display = "Missing filename. (Can't happen.)")]
@@ -51,10 +187,129 @@ enum DarkError {
         _0
     )]
     SingleFileUnsupported(String),
+    #[fail(display = "Couldn't reach {}: {}", _0, _1)]
+    Unreachable(String, String),
+    #[fail(display = "TLS handshake with {} failed: {}", _0, _1)]
+    Tls(String, String),
+    #[fail(display = "Couldn't fetch {}: the server responded {}", _0, _1)]
+    AssetFetch(String, u16),
+    #[fail(
+        display = "{} doesn't match {}:\n\tdeployed: sha256 {}\n\tlocal:    sha256 {}",
+        _0, _1, _3, _2
+    )]
+    Mismatch(String, String, String, String),
+    #[fail(
+        display = "The upload succeeded, but {} of the {} file(s) checked don't match what's deployed:\n\t{}",
+        _0, _1, _2
+    )]
+    VerificationFailed(usize, usize, String),
+    #[fail(
+        display = "{} of the {} directories failed to upload (the rest succeeded):\n\t{}",
+        _0, _1, _2
+    )]
+    // how many groups failed, of how many, why, and which (by FormGroup dir)
+    GroupsFailed(usize, usize, String, Vec<String>),
+    #[fail(display = "{} of the {} checks failed.", _0, _1)]
+    DoctorFailed(usize, usize),
+    #[fail(display = "The upload succeeded, but the purge failed: {}", _0)]
+    Purge(String),
+    #[fail(display = "Failed to watch for changes: {}", _0)]
+    Watch(String),
+    #[fail(display = "Failed to write report to {}: {}", _0, _1)]
+    Report(String, String),
+    #[fail(display = "Failed to write {}: {}", path, message)]
+    Io {
+        path: String,
+        kind: std::io::ErrorKind,
+        message: String,
+    },
     #[fail(display = "Unknown failure")]
     Unknown,
 }
 
+impl DarkError {
+    // A failure writing to `path`, spelling out the usual culprit when the disk is full.
+    fn io(path: &Path, err: &std::io::Error) -> DarkError {
+        // io::ErrorKind has no stable "storage full" kind yet, so check the OS error: ENOSPC on
+        // Unix, ERROR_HANDLE_DISK_FULL/ERROR_DISK_FULL on Windows.
+        let disk_full = if cfg!(windows) {
+            err.raw_os_error() == Some(39) || err.raw_os_error() == Some(112)
+        } else {
+            err.raw_os_error() == Some(28)
+        };
+        DarkError::Io {
+            path: path.display().to_string(),
+            kind: err.kind(),
+            message: if disk_full {
+                format!("the disk is full ({})", err)
+            } else {
+                err.to_string()
+            },
+        }
+    }
+
+    // A stable name for the kind of failure, for --output-format json.
+    fn kind(&self) -> &'static str {
+        match self {
+            DarkError::Auth(_) => "auth",
+            DarkError::AuthUnauthorized(_) => "auth_unauthorized",
+            DarkError::Forbidden(_) => "forbidden",
+            DarkError::CsrfRejected(_) => "csrf_rejected",
+            DarkError::AuthRedirect(_, _) => "auth_redirect",
+            DarkError::AuthResponse(_, _) => "auth_response",
+            DarkError::AuthLoginPage => "auth_login_page",
+            DarkError::AuthCommand(_, _) => "auth_command",
+            DarkError::Non200Response(_, _, _) => "upload_rejected",
+            DarkError::Unsupported { .. } => "unsupported",
+            DarkError::NoFilesFound(_, _) => "no_files_found",
+            DarkError::GlobMatchedNothing(_) => "glob_matched_nothing",
+            DarkError::Upload(_) => "upload",
+            DarkError::FileRead(_, _) => "file_read",
+            DarkError::FileChanged(_, _, _) => "file_changed",
+            DarkError::Config(_, _) => "config",
+            DarkError::UnknownProfile(_, _) => "unknown_profile",
+            DarkError::HostCredentials(_, _) => "host_credentials",
+            DarkError::CanvasTemplate(_) => "canvas_template",
+            DarkError::InvalidArgument(_, _) => "invalid_argument",
+            DarkError::DuplicateAsset(_, _, _) => "duplicate_asset",
+            DarkError::WarningsAsErrors(_) => "warnings_as_errors",
+            DarkError::UploadWarnings(_) => "warnings_as_errors",
+            DarkError::LockfileDrift(_, _) => "lockfile_drift",
+            DarkError::MissingLockfile(_) => "missing_lockfile",
+            DarkError::LockfileHashAlgorithm(_, _, _) => "lockfile_hash_algorithm",
+            DarkError::UncommittedChanges(_) => "uncommitted_changes",
+            DarkError::NoCredentials(_) => "no_credentials",
+            DarkError::Cancelled => "cancelled",
+            DarkError::MissingArgument(_) => "missing_argument",
+            DarkError::MissingFilename() => "missing_filename",
+            DarkError::SingleFileUnsupported(_) => "single_file_unsupported",
+            DarkError::Unreachable(_, _) => "unreachable",
+            DarkError::Tls(_, _) => "tls",
+            DarkError::AssetFetch(_, _) => "asset_fetch",
+            DarkError::Mismatch(_, _, _, _) => "mismatch",
+            DarkError::VerificationFailed(_, _, _) => "verification_failed",
+            DarkError::GroupsFailed(_, _, _, _) => "groups_failed",
+            DarkError::DoctorFailed(_, _) => "doctor_failed",
+            DarkError::Purge(_) => "purge",
+            DarkError::Watch(_) => "watch",
+            DarkError::Report(_, _) => "report",
+            DarkError::Io { .. } => "io",
+            DarkError::Unknown => "unknown",
+        }
+    }
+
+    // Most failures exit 1; `dark ping` and `dark verify` use distinct codes so scripts can tell
+    // "the server is down" from "the server's certificate is bad" from "the asset is wrong".
+    fn exit_code(&self) -> i32 {
+        match self {
+            DarkError::Unreachable(_, _) => 2,
+            DarkError::Tls(_, _) => 3,
+            DarkError::Mismatch(_, _, _, _) | DarkError::VerificationFailed(_, _, _) => 4,
+            _ => 1,
+        }
+    }
+}
+
 impl From<reqwest::Error> for DarkError {
     fn from(_err: reqwest::Error) -> Self {
         DarkError::Unknown
@@ -94,7 +349,7 @@ impl From<walkdir::Error> for DarkError {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize)]
 struct CookieAndCsrf {
     #[serde(rename = "sessionKey")]
     cookie: String,
@@ -103,316 +358,4573 @@ struct CookieAndCsrf {
     msg: Option<String>,
 }
 
-fn cookie_and_csrf(user: String, password: String) -> Result<CookieAndCsrf, DarkError> {
-    let requri = "https://login.darklang.com/dark-cli";
-    let mut authresp = match reqwest::Client::new()
-        .post(requri)
-        .header(USER_AGENT, format!("{}/{}", PKG_NAME, VERSION))
-        .header(CONTENT_TYPE, "application/json")
-        .basic_auth(user, Some(password))
-        .body("")
-        .send()
-    {
-        Ok(r) => r,
-        Err(error) => panic!("Error authing: {:?}", error),
+// By hand, so the session itself is only printed with --show-secrets-in-errors.
+impl fmt::Debug for CookieAndCsrf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CookieAndCsrf")
+            .field("cookie", &secrets::redact(&self.cookie))
+            .field("csrf", &secrets::redact(&self.csrf))
+            .field("msg", &self.msg)
+            .finish()
+    }
+}
+
+fn auth_request(
+    client: &reqwest::Client,
+    requri: &str,
+    user: &str,
+    password: &str,
+    headers: &HeaderMap,
+) -> Result<reqwest::Response, DarkError> {
+    retry::with_retries("logging in", || {
+        client
+            .post(requri)
+            .headers(headers.clone())
+            .header(USER_AGENT, format!("{}/{}", PKG_NAME, VERSION))
+            .header(CONTENT_TYPE, "application/json")
+            .basic_auth(user, Some(password))
+            .body("")
+            .send()
+    })
+    // out of retries: the login endpoint is down, or its certificate is bad
+    .map_err(|error| connection_error(requri, &error))
+}
+
+// Where a redirect response points, resolved against the url that was requested.
+fn redirect_location(requri: &str, resp: &reqwest::Response) -> Option<reqwest::Url> {
+    let location = resp.headers().get(LOCATION)?.to_str().ok()?;
+    reqwest::Url::parse(requri).ok()?.join(location).ok()
+}
+
+// Whether a login redirect from `requri` to `location` is followed, which sends the username and
+// password again: only when the scheme, host and port all stay the same, so an https login is
+// never downgraded to http, nor handed to another port or server.
+fn follows_redirect(requri: &str, location: &reqwest::Url) -> bool {
+    reqwest::Url::parse(requri)
+        .map(|requri| requri.origin() == location.origin())
+        .unwrap_or(false)
+}
+
+// Heuristic: is this an HTML page, and does it ask the user to log in?
+fn looks_like_login_page(body: &str) -> bool {
+    let body = body.to_lowercase();
+    let is_html = body.trim_start().starts_with('<') || body.contains("<html");
+    let asks_for_login = ["<form", "password", "log in", "login", "sign in"]
+        .iter()
+        .any(|marker| body.contains(marker));
+    is_html && asks_for_login
+}
+
+// Describe a WWW-Authenticate challenge, e.g. `Basic realm="dark"` becomes `Basic auth, realm
+// "dark"`. Only the first challenge is described; that's the one servers put first for a reason.
+fn describe_challenge(header: &str) -> Option<String> {
+    let header = header.trim();
+    let (scheme, params) = match header.find(' ') {
+        Some(space) => (&header[..space], &header[space + 1..]),
+        None => (header, ""),
+    };
+    if scheme.is_empty() {
+        return None;
+    }
+    let realm = params.split(',').find_map(|param| {
+        let mut param = param.splitn(2, '=');
+        match (param.next(), param.next()) {
+            (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case("realm") => {
+                Some(value.trim().trim_matches('"').to_string())
+            }
+            _ => None,
+        }
+    });
+    Some(match realm {
+        Some(realm) => format!("{} auth, realm \"{}\"", scheme, realm),
+        None => format!("{} auth", scheme),
+    })
+}
+
+fn cookie_and_csrf(
+    requri: &str,
+    user: String,
+    password: String,
+    verbosity: Verbosity,
+    headers: &HeaderMap,
+) -> Result<CookieAndCsrf, DarkError> {
+    // Logging in can take a few seconds on a slow connection; say what we're doing meanwhile.
+    let spinner = if verbosity == Verbosity::Quiet {
+        None
+    } else {
+        let host = reqwest::Url::parse(requri)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_else(|| requri.to_string());
+        Some(spinner::Spinner::start(format!(
+            "Authenticating to {} as {}...",
+            host, user
+        )))
     };
+    // Handle redirects ourselves: reqwest would silently follow them, and a login endpoint that
+    // redirects usually means the instance moved or wants SSO, which the user needs to know.
+    let client = reqwest::Client::builder()
+        .redirect(RedirectPolicy::none())
+        .build()?;
+    let mut authresp = auth_request(&client, requri, &user, &password, headers)?;
+
+    if authresp.status().is_redirection() {
+        // the url the response in hand came from
+        let mut followed = requri.to_string();
+        match redirect_location(requri, &authresp) {
+            // Following a single redirect within the same origin is safe - it's the same server,
+            // it just moved the endpoint - so do that rather than failing.
+            Some(ref location) if follows_redirect(requri, location) => {
+                authresp = auth_request(&client, location.as_str(), &user, &password, headers)?;
+                followed = location.to_string();
+            }
+            _ => (),
+        }
+        if authresp.status().is_redirection() {
+            return Err(DarkError::AuthRedirect(
+                authresp.status().as_u16(),
+                redirect_location(&followed, &authresp)
+                    .map(|url| url.to_string())
+                    .unwrap_or_else(|| "<no Location header>".to_string()),
+            ));
+        }
+    }
 
     match authresp.status() {
         StatusCode::OK => (),
+        StatusCode::UNAUTHORIZED => {
+            let challenge = authresp
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .and_then(|header| header.to_str().ok())
+                .and_then(describe_challenge)
+                .map(|challenge| format!("; it asked for {}", challenge))
+                .unwrap_or_default();
+            return Err(DarkError::AuthUnauthorized(challenge));
+        }
+        StatusCode::FORBIDDEN => {
+            return Err(DarkError::Forbidden("this Dark instance".to_string()));
+        }
         _ => {
             return Err(DarkError::Auth(authresp.status().as_u16()));
         }
     }
-    authresp
-        .json::<CookieAndCsrf>()
+    let body = authresp.text().map_err(|error| {
+        DarkError::Unreachable(
+            requri.to_string(),
+            format!("the response was cut off: {}", error),
+        )
+    })?;
+    drop(spinner);
+    serde_json::from_str::<CookieAndCsrf>(&body)
+        .map_err(|error| {
+            // A 200 that isn't our JSON is almost always a login or error page served in place
+            // of the API response - i.e. the credentials didn't work - not a bug in this tool.
+            if looks_like_login_page(&body) {
+                DarkError::AuthLoginPage
+            } else {
+                DarkError::AuthResponse(requri.to_string(), error.to_string())
+            }
+        })
         .map(|r| {
             #[cfg(debug_assertions)]
             dbg!("Cookie and csrf: {:?}", &r);
             r
         })
+        .map(|r| {
+            if verbosity == Verbosity::Verbose {
+                eprintln!("Authenticated.");
+            }
+            r
+        })
         .map(|r| {
             // Display this message, if it's set. Intent: the backend can detect via useragent if
             // you're out of date, and suggest you download the latest version.
             match &r.msg {
                 None => (),
-                Some(msg) => println!("{}", msg),
+                Some(msg) => eprintln!("{}", msg),
             };
 
             r
         })
-        .map_err(|error| panic!("Error authing: {:?}", error))
 }
 
-fn form_body(dir: &str) -> Result<(reqwest::multipart::Form, u64), DarkError> {
-    use path_slash::PathExt;
+// An unauthenticated GET against the host, to check that it's up - and that it's a Darklang
+// instance - without involving credentials.
+fn ping(host: &str) -> Result<(), DarkError> {
+    // No retries: this reports whether the host answers right now.
+    let resp = reqwest::Client::new()
+        .get(host)
+        .header(USER_AGENT, format!("{}/{}", PKG_NAME, VERSION))
+        .send();
 
-    if Path::new(dir).is_file() {
-        let err = DarkError::SingleFileUnsupported(dir.to_string());
-        // fn main doesn't pretty-print the error, so do it here
-        // https://crates.io/crates/exitfailure might wrap this nicely, if we wanted to make all
-        // errors pretty-print this way
-        println!("Error: {}", err);
-        return Err(err);
+    match resp {
+        Ok(resp) => {
+            println!("{} is reachable (responded {}).", host, resp.status());
+            if host.starts_with("https://") {
+                println!("TLS certificate is valid.");
+            }
+            match resp
+                .headers()
+                .get(SERVER)
+                .and_then(|server| server.to_str().ok())
+            {
+                Some(server) => println!("Server: {}", server),
+                None => println!("Server: <not reported>"),
+            }
+            if resp.headers().contains_key("X-Darklang-Execution-ID") {
+                println!("This looks like a Darklang instance.");
+            }
+            Ok(())
+        }
+        Err(error) => Err(connection_error(host, &error)),
     }
+}
 
-    let mut files = WalkDir::new(dir)
-        .follow_links(true)
-        .into_iter()
-        .filter_map(std::result::Result::ok)
-        .filter(|entry| entry.file_type().is_file())
-        .peekable();
-
-    // "is_empty()"
-    if files.peek().is_none() {
-        return Err(DarkError::NoFilesFound(dir.to_string()));
-    };
+// Why a request to `host` couldn't get a response: a TLS failure or the host being unreachable.
+fn connection_error(host: &str, error: &reqwest::Error) -> DarkError {
+    // reqwest doesn't distinguish TLS failures from other connection failures, so go by the
+    // description of the underlying error.
+    let description = format!("{}", error);
+    let lowercase = description.to_lowercase();
+    if ["certificate", "ssl", "tls"]
+        .iter()
+        .any(|needle| lowercase.contains(needle))
+    {
+        DarkError::Tls(host.to_string(), description)
+    } else {
+        DarkError::Unreachable(host.to_string(), description)
+    }
+}
 
-    let mut len = 0;
+// The pattern and path pairs `dark help-patterns` shows the matching of.
+const PATTERN_EXAMPLES: &[(&str, &str)] = &[
+    ("*.map", "app.js.map"),
+    ("*.map", "js/app.js.map"),
+    ("js/*.js", "js/app.js"),
+    ("js/*.js", "js/vendor/lib.js"),
+    ("**/*.css", "site.css"),
+    ("**/*.css", "css/themes/dark.css"),
+    ("vendor/**", "vendor/lib/a.js"),
+    ("*.{png,jpg}", "img/logo.png"),
+    ("logo.?ng", "logo.png"),
+    ("[._]*", ".DS_Store"),
+];
 
-    let mut form = multipart::Form::new().percent_encode_noop();
-    for file in files {
-        len += file.metadata()?.len();
-        let filename = file
-            .path()
-            // we want to leave 'some' nesting in place, and just strip the prefix.  So if build
-            // contains /static/foo.md, and we tell this binary to upload build, we want the name
-            // attached to that file to be static/foo.md so it is properly nested in gcloud
-            .strip_prefix(dir)
-            .or_else(|_| Err(DarkError::MissingFilename()))?
-            // Normalize paths to use forward slash (including on Windows):
-            .to_slash_lossy();
-        form = form.file(filename, file.path())?;
+// `dark help-patterns`: explain the two ways patterns are matched, with a table of examples run
+// through the same matchers the walk uses, or with --test-pattern, try one pattern on one path.
+fn help_patterns(matches: &ArgMatches) -> Result<(), DarkError> {
+    let yes_no = |matched| if matched { "yes" } else { "no" };
+    if let Some(pattern) = matches.value_of("test-pattern") {
+        let path = matches
+            .value_of("path")
+            .ok_or_else(|| DarkError::MissingArgument("path".to_string()))?;
+        let (as_exclude, as_path) = filter::test_pattern(pattern, path)?;
+        println!("--exclude {}: {}", pattern, yes_no(as_exclude));
+        println!("path argument <dir>/{}: {}", pattern, yes_no(as_path));
+        return Ok(());
     }
 
-    Ok((form, len))
-}
+    println!(
+        "Patterns are globs, matched against a file's asset name: its path under the directory
+being uploaded, with forward slashes (js/app.js for static/js/app.js).
 
-const VERSION: &str = env!("CARGO_PKG_VERSION");
-const PKG_NAME: &str = env!("CARGO_PKG_NAME");
+--exclude and --exclude-from patterns match the whole name, and * and ? match / too, so
+*.map leaves out every source map, at any depth.
 
-fn app() -> Result<(), DarkError> {
-    let matches = App::new("dark")
-        .version(VERSION)
-        .author("Ian Smith <ismith@darklang.com")
-        .about("dark cli")
-        .after_help("You can set credentials three ways:\n  --user and --password flags\n  env vars DARK_CLI_USER and DARK_CLI_PASSWORD\n  a netrc file at any of $NETRC, ./.netrc, or ~/.netrc\n    (see https://ec.haxx.se/usingcurl/usingcurl-netrc for format)")
-        .arg(
-            Arg::with_name("user")
-                .long("user")
-                .required(false)
-                .takes_value(true)
-                .help("Your dark username"),
-        )
-        .arg(
-            Arg::with_name("password")
-                .long("password")
-                .required(false)
-                .takes_value(true)
-                .requires("user")
-                .help("Your dark password"),
-        )
-        .arg(
-            Arg::with_name("canvas")
-                .long("canvas")
-                .required(true)
-                .takes_value(true)
-                .help("Your canvas"),
-        )
-        .arg(
-            Arg::with_name("dir")
-                .required(true)
-                .takes_value(true)
-                .help("directory to upload"),
-        )
-        .arg(
-            Arg::with_name("dry-run")
-                .long("dry-run")
-                .required(false)
-                .takes_value(false)
-                .help("Don't upload to canvas, just print request"),
-        )
-        .arg(
-            Arg::with_name("dev")
-                .long("dev")
-                .required(false)
-                .takes_value(false)
-                .help("Run against localhost - debug only."),
-        )
-        .get_matches();
+A glob in a path argument (quote it: 'static/**/*.css') is split at its first glob character.
+The directory before it is walked, and the rest is matched the way a shell would: * stays
+within one directory, and ** crosses any number of them.
 
-    let dir = matches
-        .value_of("dir")
-        .ok_or_else(|| DarkError::MissingArgument("dir".to_string()))?;
-    let canvas = matches
-        .value_of("canvas")
-        .ok_or_else(|| DarkError::MissingArgument("canvas".to_string()))?;
-    let user = matches.value_of("user");
-    let password = matches.value_of("password");
-    let host = if matches.is_present("dev") {
-        "http://darklang.localhost:8000"
-    } else {
-        "https://darklang.com"
-    };
-    let dryrun = matches.is_present("dry-run");
+  *  any characters    ?  one character    [abc]  a, b or c    {{png,jpg}}  png or jpg
 
-    // first we check for username/password in command line flags
-    let creds: Option<(String, String)> = match (user, password) {
-        (Some(user), Some(password)) => {
-            println!("Using credentials from flags.");
-            Some((user.to_string(), password.to_string()))
-        }
-        (_, _) => None,
+On macOS and Windows, whose filesystems usually ignore case, patterns do too (*.JS matches
+app.js) unless --case-sensitive-globs; elsewhere they don't, unless --ignore-case. The table
+below is matched the way this platform does by default.
+"
+    );
+    println!(
+        "{:<14} {:<22} {:<10} path argument",
+        "pattern", "path", "--exclude"
+    );
+    for &(pattern, path) in PATTERN_EXAMPLES {
+        let (as_exclude, as_path) = filter::test_pattern(pattern, path)?;
+        println!(
+            "{:<14} {:<22} {:<10} {}",
+            pattern,
+            path,
+            yes_no(as_exclude),
+            yes_no(as_path)
+        );
     }
-    .or_else(|| {
-        // then we check for env vars $DARK_CLI_USER and $DARK_CLI_PASSWORD
-        match (env::var("DARK_CLI_USER"), env::var("DARK_CLI_PASSWORD")) {
-            (Ok(username), Ok(password)) => {
-                println!("Using credentials from env vars.");
-                Some((username, password))
+    println!("\nTry your own: dark help-patterns --test-pattern '<glob>' <path>");
+    Ok(())
+}
+
+// `dark logout`: drop the cached sessions for the host an upload would use (or every host, with
+// --all), and say which ones went. Having nothing cached isn't an error.
+fn logout(matches: &ArgMatches) -> Result<(), DarkError> {
+    let host = if matches.is_present("all") {
+        None
+    } else {
+        Some(match matches.value_of("host") {
+            Some(host) => host.trim_end_matches('/').to_string(),
+            None if matches.is_present("dev") => "http://darklang.localhost:8000".to_string(),
+            None => match env_host() {
+                Some(host) => host,
+                None => config::load(None, true)?
+                    .host
+                    .unwrap_or_else(|| DEFAULT_HOST.to_string()),
+            },
+        })
+    };
+    let cleared = cache::clear(host.as_ref().map(String::as_str))?;
+    match host {
+        _ if !cleared.is_empty() => {
+            for session in cleared {
+                println!("Cleared the cached session for {}.", session);
             }
-            _ => None,
         }
-    })
-    .or_else(|| {
-        // then we try netrc, via (in order):
-        // - the file at $NETRC
-        // - the file at ./.netrc
-        // - the file at ~/.netrc
-        let netrc_home = dirs::home_dir()
-            .and_then(|mut netrc_home| {
-                netrc_home.push(".netrc");
-                Some(netrc_home)
-            })
-            .unwrap_or_default();
-
-        let netrc_env = env::var("NETRC").unwrap_or_default();
-
-        let netrc_path: &str = if Path::new(&netrc_env).is_file() {
-            netrc_env.as_str()
-        } else if Path::new("./.netrc").is_file() {
-            "./.netrc"
-        } else if Path::new(&netrc_home).is_file() {
-            netrc_home.to_str().unwrap_or_default()
-        } else {
-            ""
-        };
-        let netrc = File::open(netrc_path)
-            .ok()
-            .map(BufReader::new)
-            .and_then(|bufr| Netrc::parse(bufr).ok());
+        Some(host) => println!("No cached session for {}; nothing to clear.", host),
+        None => println!("No cached sessions; nothing to clear."),
+    }
+    Ok(())
+}
 
-        let netrc_machine: Option<String> = host
-            .parse::<Uri>()
-            .and_then(|uri| {
-                Ok(match uri.host() {
-                    Some(h) => h.to_owned(),
-                    _ => "".to_owned(),
-                })
-            })
-            .ok();
+// `dark doctor`: run the checks against the host an upload would use, with the credentials it
+// would use. Credentials that can't be worked out (say, a bad [hosts] table) are a failed check,
+// not an error.
+fn doctor_command(matches: &ArgMatches) -> Result<(), DarkError> {
+    let settings = config::load(matches.value_of("profile"), true)?;
+    let host = match matches.value_of("host") {
+        Some(host) => host.trim_end_matches('/').to_string(),
+        None if matches.is_present("dev") => "http://darklang.localhost:8000".to_string(),
+        None => env_host()
+            .or_else(|| settings.host.clone())
+            .unwrap_or_else(|| DEFAULT_HOST.to_string()),
+    };
+    let creds = find_credentials(
+        matches.value_of("user"),
+        matches.value_of("password"),
+        &settings,
+        &host,
+    );
+    let login_url = login_url(matches.value_of("login-url"), &settings, &host);
+    doctor::run(&host, &login_url, creds)
+}
 
-        let netrc_creds: Option<(String, String)> = match (netrc, netrc_machine) {
-            (Some(netrc), Some(netrc_machine)) => netrc
-                .hosts
-                .iter()
-                .find(|(k, _nm)| *k == netrc_machine)
-                .map(|(_, nm)| {
-                    (
-                        nm.login.clone(),
-                        nm.password.as_ref().unwrap_or(&"".to_string()).clone(),
-                    )
-                }),
-            (_, _) => None,
-        };
+// Where `host` serves `canvas`: Dark puts each canvas on its own builtwithdark subdomain of the
+// host's domain, so https://darklang.com serves myapp at https://myapp.builtwithdark.com, and
+// http://darklang.localhost:8000 at http://myapp.builtwithdark.localhost:8000. None for a host
+// that isn't named darklang.<domain>, whose canvases could be anywhere.
+fn canvas_base_url(host: &str, canvas: &str) -> Option<String> {
+    let mut url = reqwest::Url::parse(host).ok()?;
+    let domain = url.host_str()?.to_string();
+    if !domain.starts_with("darklang.") {
+        return None;
+    }
+    let canvas_domain = format!("{}.builtwithdark.{}", canvas, &domain["darklang.".len()..]);
+    url.set_host(Some(&canvas_domain)).ok()?;
+    url.set_path("");
+    Some(url.as_str().trim_end_matches('/').to_string())
+}
 
-        match netrc_creds {
-            Some(_) => {
-                println!("Using credentials from netrc at {}.", netrc_path);
-                netrc_creds
-            }
-            _ => None,
+// `dark verify`: work out the asset's URL from the canvas (or --base-url) and the local path (or
+// asset-name), and check that it matches the local file.
+fn verify_command(matches: &ArgMatches) -> Result<(), DarkError> {
+    let base_url = match matches.value_of("base-url") {
+        Some(base_url) => base_url.to_string(),
+        None => {
+            // the canvas and host come from the same places as for an upload
+            let settings = config::load(None, true)?;
+            let canvas = matches
+                .value_of("canvas")
+                .map(str::to_string)
+                .or_else(|| settings.canvas.clone())
+                .ok_or_else(|| DarkError::MissingArgument("canvas".to_string()))?;
+            let host = match matches.value_of("host") {
+                Some(host) => host.trim_end_matches('/').to_string(),
+                None if matches.is_present("dev") => "http://darklang.localhost:8000".to_string(),
+                None => env_host()
+                    .or_else(|| settings.host.clone())
+                    .unwrap_or_else(|| DEFAULT_HOST.to_string()),
+            };
+            canvas_base_url(&host, &canvas).ok_or_else(|| {
+                DarkError::InvalidArgument(
+                    "base-url".to_string(),
+                    format!(
+                        "can't tell where {} serves canvas {}; pass --base-url",
+                        host, canvas
+                    ),
+                )
+            })?
         }
-    });
-
-    let (user, password) = match creds {
-        Some(c) => c,
+    };
+    let local = matches
+        .value_of("local-path")
+        .ok_or_else(|| DarkError::MissingArgument("local-path".to_string()))?;
+    // By default the asset is named like the local path, as if it had been uploaded from the
+    // current directory.
+    let asset = match matches.value_of("asset-name") {
+        Some(asset) => asset.to_string(),
         None => {
-            println!("No credentials set for {}.", host);
-            std::process::exit(1)
+            use path_slash::PathExt;
+            let local = Path::new(local);
+            local.strip_prefix(".").unwrap_or(local).to_slash_lossy()
         }
     };
+    let url = asset_url(&base_url, &asset)?;
+    verify(url.as_str(), Path::new(local))
+}
 
-    let CookieAndCsrf {
-        cookie,
-        csrf,
-        msg: _msg,
-    } = cookie_and_csrf(user, password)?;
+// Where asset `name` is served under `base_url`, with each of the name's segments percent-encoded,
+// so `my file.txt` or `a#b.css` is asked for as itself rather than as a fragment or query.
+fn asset_url(base_url: &str, name: &str) -> Result<reqwest::Url, DarkError> {
+    let invalid = |why| DarkError::InvalidArgument("base-url".to_string(), why);
+    let mut url = reqwest::Url::parse(base_url)
+        .map_err(|err| invalid(format!("{} isn't a URL ({})", base_url, err)))?;
+    url.path_segments_mut()
+        .map_err(|_| invalid(format!("{} can't have paths under it", base_url)))?
+        .pop_if_empty()
+        .extend(name.trim_start_matches('/').split('/'));
+    Ok(url)
+}
 
-    let (form, size) = form_body(&dir.to_string())?;
+// Download the asset at `url` and check that it's byte-for-byte the same as the local file.
+fn verify(url: &str, local: &Path) -> Result<(), DarkError> {
+    let local_sha256 = hash_file(HashAlgorithm::Sha256, local)
+        .map_err(|error| DarkError::FileRead(local.display().to_string(), error))?;
+    let remote_sha256 = download_hash(&reqwest::Client::new(), url, HashAlgorithm::Sha256)?;
 
-    println!(
-        "Going to attempt to upload files totalling {}.",
-        size.file_size(options::DECIMAL)?
-    );
+    if remote_sha256 == local_sha256 {
+        println!(
+            "{} matches {} (sha256 {}).",
+            url,
+            local.display(),
+            local_sha256
+        );
+        Ok(())
+    } else {
+        Err(DarkError::Mismatch(
+            url.to_string(),
+            local.display().to_string(),
+            local_sha256,
+            remote_sha256,
+        ))
+    }
+}
 
-    let requri = format!("{}/api/{}/static_assets", host, canvas);
-    let client = reqwest::Client::builder()
-        .gzip(true)
-        .timeout(None)
-        .build()?;
-    let req = client
-        .post(&requri)
-        .header(
-            "cookie",
+// The `algorithm` hash of what's served at `url`.
+fn download_hash(
+    client: &reqwest::Client,
+    url: &str,
+    algorithm: HashAlgorithm,
+) -> Result<String, DarkError> {
+    let mut resp = retry::with_retries("downloading the asset", || {
+        client
+            .get(url)
+            .header(USER_AGENT, format!("{}/{}", PKG_NAME, VERSION))
+            .send()
+    })
+    .map_err(|error| connection_error(url, &error))?;
+    if resp.status() != StatusCode::OK {
+        return Err(DarkError::AssetFetch(
+            url.to_string(),
+            resp.status().as_u16(),
+        ));
+    }
+    algorithm.hex_reader(&mut resp).map_err(|error| {
+        DarkError::Unreachable(url.to_string(), format!("the download failed: {}", error))
+    })
+}
+
+// The settings for an upload, as resolved from the command line.
+struct UploadOptions<'a> {
+    host: &'a str,
+    // where to log in, per login_url()
+    login_url: String,
+    canvas: String,
+    dirs: Vec<&'a str>,
+    dryrun: bool,
+    strict: bool,
+    field_name: Option<&'a str>,
+    checksums: bool,
+    // --cache-control: the policy sent alongside each file, by asset name
+    cache_control: cache_control::CacheControl,
+    // the hash length, with --name-from-hash
+    hash_names: Option<usize>,
+    manifest: Option<&'a str>,
+    trim_prefix: Option<&'a str>,
+    // --release: upload everything under releases/<id>/
+    release: Option<&'a str>,
+    // Some(true)/Some(false) for --list-files/--no-list-files; None lists only short lists
+    list_files: Option<bool>,
+    csrf_header: &'a str,
+    // the gzip level, with --compress
+    compress: Option<u32>,
+    output_format: OutputFormat,
+    // reuse (and save) sessions in the session cache; off with --no-cache
+    use_cache: bool,
+    // which files under each dir were asked for, when a path argument was a glob
+    paths: filter::Paths<'a>,
+    // with --allow-empty, a glob that matches nothing isn't an error
+    allow_empty: bool,
+    filters: filter::Filters,
+    list_excluded: bool,
+    verbosity: Verbosity,
+    print_curl: bool,
+    show_secrets: bool,
+    normalize_text: bool,
+    sanitize_names: bool,
+    warnings_as_errors: bool,
+    size_breakdown: bool,
+    show_response: bool,
+    // with --stdin-name, upload stdin as this one file instead of walking dirs
+    stdin_name: Option<&'a str>,
+    // the Content-Type for the --stdin-name file, if not guessed from its name
+    content_type: Option<&'a str>,
+    // where to tell the CDN which paths to purge after an upload, and the token to do it with
+    purge_url: Option<&'a str>,
+    purge_token: Option<String>,
+    purge_required: bool,
+    size_units: SizeUnits,
+    // --yes: don't ask before uploading to production
+    assume_yes: bool,
+    multipart_boundary: Option<&'a str>,
+    // --header: extra headers for the login and upload requests
+    headers: HeaderMap,
+    // with --lockfile (or --frozen), where uploads are recorded and checked against; with
+    // --frozen, any difference is an error
+    lockfile: Option<&'a str>,
+    frozen: bool,
+    // with --auth-command, a helper that prints the session to use, instead of logging in
+    auth_command: Option<&'a str>,
+    // --timings: say how long logging in, walking and uploading took
+    timings: bool,
+    // --output-format yaml, for the manifest and --print-config
+    yaml: bool,
+    // --map-names-from: the deployed name for particular files, by canonical path
+    name_map: BTreeMap<PathBuf, String>,
+    // follow symlinks that point outside every dir; off unless --allow-external-links
+    allow_external_links: bool,
+    // stop at a failed login, before walking; off with --no-fail-fast-auth
+    fail_fast_auth: bool,
+    // whether each file's hash is computed during the walk: --checksums, --name-from-hash,
+    // --since-last-deploy, --lockfile and the manifest all need it before anything is sent
+    hash_files: bool,
+    // whether it's worked out from the upload's body as it's sent instead, for --report and
+    // --verify-after-upload, which only need it afterwards; that spares each file a second read
+    hash_as_sent: bool,
+    // --hash-algorithm: what every one of those hashes is
+    hash_algorithm: HashAlgorithm,
+    // with --since-last-deploy, the hash of each asset as of the last deploy to this canvas;
+    // files that still match are left out
+    last_deploy: Option<BTreeMap<String, String>>,
+    // with --verify-after-upload, where the assets are served from (--base-url), and how many of
+    // them to check (--verify-sample; all of them if None)
+    verify_base_url: Option<&'a str>,
+    verify_sample: Option<usize>,
+    // --group-by-directory: one request per top-level directory
+    group_by_directory: bool,
+}
+
+// How much progress chatter to print, per --quiet/--verbose.
+#[derive(Clone, Copy, PartialEq)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+// Whether sizes are printed in powers of 1000 (KB, MB) or 1024 (KiB, MiB), per --size-units.
+#[derive(Clone, Copy, PartialEq)]
+enum SizeUnits {
+    Decimal,
+    Binary,
+}
+
+impl SizeUnits {
+    fn format(self, size: u64) -> Result<String, DarkError> {
+        Ok(size.file_size(match self {
+            SizeUnits::Decimal => options::DECIMAL,
+            SizeUnits::Binary => options::BINARY,
+        })?)
+    }
+}
+
+// How the file list and summary are printed, per --output-format.
+#[derive(Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Text,
+    Json,
+    Tsv,
+}
+
+// A file that went into the form, with the asset name it was uploaded as (and the name it would
+// have had, if --name-from-hash renamed it). `hash` is the digest of what's uploaded, computed
+// once: during the walk when opts.hash_files says something needs it before the upload, or from
+// the body as it's sent with opts.hash_as_sent. Everything that wants a file's hash reads it from
+// here rather than reading the file again.
+struct UploadEntry {
+    name: String,
+    renamed_from: Option<String>,
+    path: PathBuf,
+    size: u64,
+    hash: Option<String>,
+    // what --normalize-text changed in the uploaded copy, if anything
+    normalized: Vec<&'static str>,
+}
+
+// One request's worth of an upload: every file, or with --group-by-directory, the files under one
+// top-level directory (`dir`; "" for the files at the top level, and for an ungrouped upload).
+struct FormGroup {
+    dir: String,
+    form: multipart::Form,
+    files: usize,
+    size: u64,
+}
+
+// What form_body collected: the multipart forms to send, the files in them and their total
+// size, the path of and a warning for each file that had to be skipped because it couldn't be
+// read, every warning the walk produced (those, plus unreadable paths and renames), the files
+// --since-last-deploy left out as unchanged (by name, with their sizes), and (when
+// opts.hash_files) the asset name and hash of every file, including those.
+struct FormBody {
+    forms: Vec<FormGroup>,
+    entries: Vec<UploadEntry>,
+    size: u64,
+    skipped: Vec<(String, String)>,
+    warnings: Vec<String>,
+    unchanged: Vec<(String, u64)>,
+    hashes: BTreeMap<String, String>,
+}
+
+// How long each phase of a run took, for --timings; a phase the run didn't get to is None.
+#[derive(Default)]
+struct Timings {
+    auth: Option<Duration>,
+    // walking the dirs, and hashing the files if anything needs their hashes
+    walk: Option<Duration>,
+    upload: Option<Duration>,
+}
+
+// The files and warnings from a run, kept whether or not the upload succeeds so --report can
+// describe it either way.
+#[derive(Default)]
+struct UploadLog {
+    entries: Vec<UploadEntry>,
+    warnings: Vec<String>,
+    timings: Timings,
+    // every file's asset name and hash, for --lockfile
+    hashes: BTreeMap<String, String>,
+    // the files that couldn't be read (and why), and those --since-last-deploy left out
+    skipped: Vec<(String, String)>,
+    unchanged: Vec<(String, u64)>,
+}
+
+// Write `contents` to `path` via a temp file in the same directory and a rename, so a failure
+// part way through (say, a full disk) never leaves a truncated file behind.
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), DarkError> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(".tmp-{}", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let written = File::create(&tmp_path)
+        .and_then(|mut file| file.write_all(contents).and_then(|_| file.sync_all()))
+        .and_then(|_| std::fs::rename(&tmp_path, path));
+    written.map_err(|err| {
+        let _ = std::fs::remove_file(&tmp_path);
+        DarkError::io(path, &err)
+    })
+}
+
+// Insert (the first `length` characters of) a file's hash before its extension, so
+// js/app.js becomes js/app.1a2b3c4d.js.
+fn hashed_name(name: &str, hash: &str, length: usize) -> String {
+    let hash = &hash[..length.min(hash.len())];
+    let (dir, file) = match name.rfind('/') {
+        Some(slash) => name.split_at(slash + 1),
+        None => ("", name),
+    };
+    match file.rfind('.') {
+        // a leading dot (.htaccess) isn't an extension
+        Some(dot) if dot > 0 => format!("{}{}.{}{}", dir, &file[..dot], hash, &file[dot..]),
+        _ => format!("{}{}.{}", dir, file, hash),
+    }
+}
+
+// Write the original -> hashed name mapping from --name-from-hash to `path` as JSON, so build
+// tools can rewrite references. With `merge`, entries already in the file are kept (for
+// --watch, which only uploads what changed).
+fn write_name_manifest(path: &str, entries: &[UploadEntry], merge: bool) -> Result<(), DarkError> {
+    let mut names: BTreeMap<String, String> = if merge {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    } else {
+        BTreeMap::new()
+    };
+    for entry in entries {
+        if let Some(ref original) = entry.renamed_from {
+            names.insert(original.clone(), entry.name.clone());
+        }
+    }
+    let contents = serde_json::to_string_pretty(&names).unwrap_or_default();
+    write_atomically(Path::new(path), contents.as_bytes())
+}
+
+fn hash_file(algorithm: HashAlgorithm, path: &Path) -> std::io::Result<String> {
+    algorithm.hex_reader(&mut File::open(path)?)
+}
+
+// Make a multipart part for a single file, named `name`, returning it along with its size. The
+// file isn't read until the body is sent, but we open it here so an unreadable file is caught
+// (and skipped) before we start uploading.
+fn file_part(path: &Path, name: &str) -> std::io::Result<(multipart::Part, u64)> {
+    // For a symlink, read the target's contents (and guess its mime type from the target), but
+    // keep the link's name (its path under the dir, which is `name`), so `latest ->
+    // build-123/app.js` uploads as `latest`. A link whose target has gone since the walk is
+    // skipped like any other file that can't be read.
+    let source = if std::fs::symlink_metadata(path)?.file_type().is_symlink() {
+        std::fs::canonicalize(path).map_err(|err| {
+            std::io::Error::new(
+                err.kind(),
+                format!(
+                    "{} is a symlink to a missing file ({})",
+                    path.display(),
+                    err
+                ),
+            )
+        })?
+    } else {
+        path.to_path_buf()
+    };
+    let size = std::fs::metadata(&source)?.len();
+    File::open(&source)?;
+    Ok((multipart::Part::file(&source, name, size), size))
+}
+
+fn form_body(opts: &UploadOptions) -> Result<FormBody, DarkError> {
+    let mut files = vec![];
+    let mut excluded = vec![];
+    // the glob path arguments that matched at least one file, and how many files matched none
+    let mut matched = BTreeSet::new();
+    let mut unmatched = 0;
+    // where the dirs really are, so a symlink can be checked for pointing outside all of them
+    let roots: Vec<PathBuf> = if opts.allow_external_links {
+        vec![]
+    } else {
+        opts.dirs
+            .iter()
+            .filter_map(|dir| std::fs::canonicalize(dir).ok())
+            .collect()
+    };
+    for &dir in &opts.dirs {
+        if Path::new(dir).is_file() {
+            let err = DarkError::SingleFileUnsupported(dir.to_string());
+            // fn main doesn't pretty-print the error, so do it here
+            // https://crates.io/crates/exitfailure might wrap this nicely, if we wanted to make all
+            // errors pretty-print this way
+            if opts.output_format == OutputFormat::Text {
+                println!("Error: {}", err);
+            }
+            return Err(err);
+        }
+
+        let mut walker = WalkDir::new(dir).follow_links(true).into_iter();
+        while let Some(entry) = walker.next() {
+            // A link out of the tree (say, to /etc) is almost always a mistake, so leave it, and
+            // everything under it, out unless --allow-external-links.
+            let external = match entry {
+                Ok(ref entry) if !roots.is_empty() && entry.path_is_symlink() => {
+                    std::fs::canonicalize(entry.path())
+                        .ok()
+                        .filter(|target| !roots.iter().any(|root| target.starts_with(root)))
+                        .map(|target| (entry.path().to_path_buf(), target, entry.file_type()))
+                }
+                _ => None,
+            };
+            if let Some((link, target, file_type)) = external {
+                excluded.push(filter::Excluded {
+                    path: asset_name(dir, &link)?,
+                    rule: "external-link",
+                    reason: format!(
+                        "links to {}, outside the uploaded dirs (see --allow-external-links)",
+                        target.display()
+                    ),
+                });
+                if file_type.is_dir() {
+                    walker.skip_current_dir();
+                }
+                continue;
+            }
+            match entry {
+                Ok(ref entry) if entry.file_type().is_dir() => (),
+                Ok(entry) => {
+                    let name = asset_name(dir, entry.path())?;
+                    match opts.paths.matches(dir, &name) {
+                        Some(globs) => matched.extend(globs),
+                        None => {
+                            unmatched += 1;
+                            continue;
+                        }
+                    }
+                    let exclusion = if !entry.file_type().is_file() {
+                        Some(filter::Excluded {
+                            path: name,
+                            rule: "special",
+                            reason: "not a regular file".to_string(),
+                        })
+                    } else {
+                        let size = entry.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+                        opts.filters.exclusion(&name, size)
+                    };
+                    match exclusion {
+                        Some(exclusion) => excluded.push(exclusion),
+                        None => files.push((dir, entry.into_path())),
+                    }
+                }
+                // e.g. a dangling symlink, or a directory we can't list
+                Err(err) => excluded.push(filter::Excluded {
+                    path: err
+                        .path()
+                        .and_then(|path| asset_name(dir, path).ok())
+                        .unwrap_or_else(|| dir.to_string()),
+                    rule: "unreadable",
+                    reason: match err.io_error() {
+                        Some(io_error) => format!("couldn't be read ({})", io_error),
+                        None => format!("couldn't be read ({})", err),
+                    },
+                }),
+            }
+        }
+    }
+    if opts.list_excluded {
+        print_excluded(opts, &excluded);
+    }
+    let mut unreadable: Vec<String> = excluded
+        .iter()
+        .filter(|file| file.rule == "unreadable" || file.rule == "external-link")
+        .map(|file| format!("{} {}; not uploaded.", file.path, file.reason))
+        .collect();
+    for glob in opts.paths.globs() {
+        if !matched.contains(glob) {
+            if !opts.allow_empty {
+                return Err(DarkError::GlobMatchedNothing(glob.to_string()));
+            }
+            unreadable.push(format!("{} matched no files.", glob));
+        }
+    }
+    let too_small = excluded
+        .iter()
+        .filter(|file| file.rule == "min-file-size" || file.rule == "skip-empty")
+        .count();
+    if too_small > 0 && opts.output_format == OutputFormat::Text {
+        println!(
+            "Skipped {} empty or too-small file(s) (see --min-file-size/--skip-empty).",
+            too_small
+        );
+    }
+
+    if files.is_empty() && (unmatched > 0 || !excluded.is_empty()) {
+        return Err(DarkError::NoFilesFound(
+            opts.dirs.join(", "),
+            describe_exclusions(unmatched, &excluded),
+        ));
+    }
+    let mut body = form_from_files(opts, files)?;
+    body.warnings.splice(0..0, unreadable);
+    Ok(body)
+}
+
+// What left every file out, for NoFilesFound, e.g. " (the walk found 120 file(s); --exclude removed
+// 100, --min-file-size removed 20; 0 remain)".
+fn describe_exclusions(unmatched: usize, excluded: &[filter::Excluded]) -> String {
+    let mut removed: BTreeMap<&str, usize> = BTreeMap::new();
+    for file in excluded {
+        *removed.entry(file.rule).or_insert(0) += 1;
+    }
+    let mut removed: Vec<String> = removed
+        .into_iter()
+        .map(|(rule, count)| match rule {
+            "special" => format!("{} weren't regular files", count),
+            "unreadable" => format!("{} path(s) couldn't be read", count),
+            "external-link" => format!("{} link(s) pointed outside the dirs", count),
+            _ => format!("--{} removed {}", rule, count),
+        })
+        .collect();
+    if unmatched > 0 {
+        removed.insert(0, format!("{} didn't match the globs", unmatched));
+    }
+    // an unreadable path or external link may be a whole directory (or the dir itself), so it
+    // isn't counted as a file found
+    let found = unmatched
+        + excluded
+            .iter()
+            .filter(|file| file.rule != "unreadable" && file.rule != "external-link")
+            .count();
+    if found > 0 {
+        format!(
+            " (the walk found {} file(s); {}; 0 remain)",
+            found,
+            removed.join(", ")
+        )
+    } else {
+        format!(" ({}; 0 remain)", removed.join(", "))
+    }
+}
+
+// The name a file under `dir` is uploaded as.
+fn asset_name(dir: &str, path: &Path) -> Result<String, DarkError> {
+    use path_slash::PathExt;
+
+    Ok(path
+        // we want to leave 'some' nesting in place, and just strip the prefix.  So if build
+        // contains /static/foo.md, and we tell this binary to upload build, we want the name
+        // attached to that file to be static/foo.md so it is properly nested in gcloud
+        .strip_prefix(dir)
+        .or_else(|_| Err(DarkError::MissingFilename()))?
+        // Normalize paths to use forward slash (including on Windows):
+        .to_slash_lossy())
+}
+
+// `name` with the leading directories in `prefix` removed, so with a prefix of static,
+// static/js/app.js becomes js/app.js. None if `name` isn't under `prefix`.
+fn trim_prefix(name: &str, prefix: &str) -> Option<String> {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        return Some(name.to_string());
+    }
+    if name.starts_with(prefix) && name[prefix.len()..].starts_with('/') {
+        Some(name[prefix.len() + 1..].to_string())
+    } else {
+        None
+    }
+}
+
+// `name` made safe to serve at a URL, for --sanitize-names: spaces become dashes, ?, # and
+// control characters (which would end or mangle the URL's path) are dropped, and runs of
+// slashes collapse into one.
+fn sanitize_name(name: &str) -> String {
+    let mut sanitized = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            ' ' => sanitized.push('-'),
+            '?' | '#' => (),
+            _ if c.is_control() => (),
+            '/' if sanitized.is_empty() || sanitized.ends_with('/') => (),
+            _ => sanitized.push(c),
+        }
+    }
+    sanitized
+}
+
+// Which FormGroup the file named `name` goes in: with --group-by-directory, the one for its
+// top-level directory ("" for a file at the top level); otherwise the only one.
+fn group_dir(opts: &UploadOptions, name: &str) -> String {
+    match name.find('/') {
+        Some(slash) if opts.group_by_directory => name[..slash].to_string(),
+        _ => String::new(),
+    }
+}
+
+// How a FormGroup is named in progress and errors.
+fn group_label(dir: &str) -> String {
+    if dir.is_empty() {
+        "the top-level files".to_string()
+    } else {
+        format!("{}/", dir)
+    }
+}
+
+// Send each of `forms` that isn't in `done` (by index), adding those that succeed, so a retry
+// after logging in again picks up where the last attempt stopped. With --group-by-directory, a
+// group that fails doesn't stop the rest, and the failures are reported together at the end;
+// the exception is the session being rejected, which would fail every group, so that's returned
+// straight away for login_and_upload to log in again.
+fn upload_forms(
+    opts: &UploadOptions,
+    session: &CookieAndCsrf,
+    forms: &[FormGroup],
+    done: &mut BTreeSet<usize>,
+) -> Result<(), DarkError> {
+    let mut failed = vec![];
+    for (index, group) in forms.iter().enumerate() {
+        if done.contains(&index) {
+            continue;
+        }
+        if opts.group_by_directory
+            && opts.verbosity != Verbosity::Quiet
+            && opts.output_format == OutputFormat::Text
+        {
+            println!(
+                "Uploading {} ({} file(s), {}; {} of {}).",
+                group_label(&group.dir),
+                group.files,
+                opts.size_units.format(group.size)?,
+                index + 1,
+                forms.len()
+            );
+        }
+        match upload(opts, session, &group.form) {
+            Ok(()) => {
+                done.insert(index);
+            }
+            Err(err) if !opts.group_by_directory => return Err(err),
+            Err(err @ DarkError::Non200Response(_, 401, _)) => return Err(err),
+            Err(err @ DarkError::CsrfRejected(_)) => return Err(err),
+            Err(err) => failed.push((group.dir.clone(), err.to_string())),
+        }
+    }
+    if failed.is_empty() {
+        return Ok(());
+    }
+    let details = failed
+        .iter()
+        .map(|&(ref dir, ref err)| format!("{}: {}", group_label(dir), err))
+        .collect::<Vec<_>>()
+        .join("\n\t");
+    Err(DarkError::GroupsFailed(
+        failed.len(),
+        forms.len(),
+        details,
+        failed.into_iter().map(|(dir, _)| dir).collect(),
+    ))
+}
+
+// An empty form, with the --multipart-boundary if there is one.
+fn new_form(opts: &UploadOptions) -> multipart::Form {
+    let form = match opts.multipart_boundary {
+        Some(boundary) => multipart::Form::with_boundary(boundary.to_string()),
+        None => multipart::Form::new(),
+    };
+    if opts.hash_as_sent {
+        form.hash_as_sent(opts.hash_algorithm)
+    } else {
+        form
+    }
+}
+
+// With opts.hash_as_sent, fill in the hashes the walk left out from what the upload sent. A file
+// that wasn't all sent (in a dry run, or an upload that failed partway) is left without one, and
+// the report reads it itself.
+fn fill_sent_hashes(opts: &UploadOptions, forms: &[FormGroup], entries: &mut [UploadEntry]) {
+    if !opts.hash_as_sent {
+        return;
+    }
+    for entry in entries.iter_mut().filter(|entry| entry.hash.is_none()) {
+        entry.hash = forms
+            .iter()
+            .find_map(|group| group.form.sent_hash(&entry.path));
+    }
+}
+
+// Build the form from stdin alone, as a single file named `name`, for --stdin-name.
+fn form_from_stdin(opts: &UploadOptions, name: &str) -> Result<FormBody, DarkError> {
+    let mut contents = vec![];
+    std::io::stdin()
+        .read_to_end(&mut contents)
+        .map_err(|error| DarkError::FileRead("stdin".to_string(), error))?;
+    let hash = opts.hash_algorithm.hex(&contents);
+    let filename = release_name(opts, name.trim_start_matches('/').to_string());
+    let name = match opts.hash_names {
+        Some(length) => hashed_name(&filename, &hash, length),
+        None => filename.clone(),
+    };
+    let size = contents.len() as u64;
+    let mime = opts
+        .content_type
+        .map(str::to_string)
+        .unwrap_or_else(|| multipart::guess_mime(Path::new(&name)));
+
+    let mut form = new_form(opts).part(
+        opts.field_name.unwrap_or(&name).to_string(),
+        multipart::Part::stdin(&name, contents, &mime),
+    );
+    if opts.checksums {
+        form = form.text(
+            format!("{}.{}", name, opts.hash_algorithm.name()),
+            hash.clone(),
+        );
+    }
+    if let Some(policy) = opts.cache_control.policy(&name) {
+        form = form.text(format!("{}.cache-control", name), policy.to_string());
+    }
+    let mut hashes = BTreeMap::new();
+    hashes.insert(name.clone(), hash.clone());
+    Ok(FormBody {
+        hashes,
+        forms: vec![FormGroup {
+            dir: String::new(),
+            form,
+            files: 1,
+            size,
+        }],
+        entries: vec![UploadEntry {
+            renamed_from: if name != filename {
+                Some(filename)
+            } else {
+                None
+            },
+            name,
+            path: PathBuf::from("-"),
+            size,
+            hash: Some(hash),
+            normalized: vec![],
+        }],
+        size,
+        skipped: vec![],
+        warnings: vec![],
+        unchanged: vec![],
+    })
+}
+
+// The asset name for `file` under `dir`: its path under dir, with --trim-prefix and
+// --sanitize-names applied.
+fn computed_name(opts: &UploadOptions, dir: &str, file: &Path) -> Result<String, DarkError> {
+    let filename = asset_name(dir, file)?;
+    let filename = match opts.trim_prefix {
+        Some(prefix) => match trim_prefix(&filename, prefix) {
+            Some(trimmed) => trimmed,
+            None if opts.strict => {
+                return Err(DarkError::InvalidArgument(
+                    "trim-prefix".to_string(),
+                    format!("{} doesn't start with {}", filename, prefix),
+                ))
+            }
+            None => filename,
+        },
+        None => filename,
+    };
+    let filename = if opts.sanitize_names {
+        let sanitized = sanitize_name(&filename);
+        // a rename is what was asked for, so it's logged rather than warned about, which would
+        // fail --warnings-as-errors
+        if sanitized != filename
+            && opts.output_format == OutputFormat::Text
+            && opts.verbosity != Verbosity::Quiet
+        {
+            println!("Sanitized {:?} to {}.", filename, sanitized);
+        }
+        sanitized
+    } else {
+        filename
+    };
+    Ok(filename)
+}
+
+// A --release id, which becomes one directory in the asset names, so it can't be empty or have a
+// slash in it.
+fn check_release(id: &str) -> Result<&str, DarkError> {
+    if id.is_empty() || id.contains('/') || id == "." || id == ".." {
+        return Err(DarkError::InvalidArgument(
+            "release".to_string(),
             format!(
-                "__session={}; Max-Age=604800; domain=darklang.com; path=/; secure; httponly",
-                cookie
+                "{:?} isn't a release id; use something like v1.2.3 or 2020-05-01",
+                id
+            ),
+        ));
+    }
+    Ok(id)
+}
+
+// `name` under releases/<id>/ with --release, or as it is.
+fn release_name(opts: &UploadOptions, name: String) -> String {
+    match opts.release {
+        Some(id) => format!("releases/{}/{}", id, name),
+        None => name,
+    }
+}
+
+// How a file is looked up in the --map-names-from map: its canonical path, so dist/a.js,
+// ./dist/a.js and an absolute path all name the same file.
+fn name_map_key(file: &Path) -> PathBuf {
+    std::fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf())
+}
+
+// Read a --map-names-from file: `local-path = deployed-name` pairs, as TOML, or JSON if the file
+// ends in .json (the --manifest output of --name-from-hash, say). Each local path must exist.
+fn read_name_map(path: &str) -> Result<BTreeMap<PathBuf, String>, DarkError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|error| DarkError::FileRead(path.to_string(), error))?;
+    let pairs: BTreeMap<String, String> = if path.ends_with(".json") {
+        serde_json::from_str(&contents).map_err(|err| err.to_string())
+    } else {
+        toml::from_str(&contents).map_err(|err| err.to_string())
+    }
+    .map_err(|message| DarkError::Config(path.to_string(), message))?;
+
+    let mut map = BTreeMap::new();
+    for (local, deployed) in pairs {
+        let key = std::fs::canonicalize(&local).map_err(|err| {
+            DarkError::InvalidArgument(
+                "map-names-from".to_string(),
+                format!("{} maps {}, which can't be found ({})", path, local, err),
+            )
+        })?;
+        map.insert(key, deployed.trim_start_matches('/').to_string());
+    }
+    Ok(map)
+}
+
+// Build the form from an already-known list of files, each with the dir it's under; form_body
+// uses this after walking the whole tree, --watch with just the files that changed.
+//
+// By default each file's part is named after the file; `field_name` instead gives every part the
+// same name (e.g. files[]), for servers that only look at the filename in Content-Disposition.
+fn form_from_files(
+    opts: &UploadOptions,
+    files: Vec<(&str, PathBuf)>,
+) -> Result<FormBody, DarkError> {
+    if files.is_empty() {
+        return Err(DarkError::NoFilesFound(opts.dirs.join(", "), String::new()));
+    };
+
+    let mut size = 0;
+    let mut entries = vec![];
+    let mut skipped = vec![];
+    let mut warnings = vec![];
+    let mut unchanged = vec![];
+    let mut hashes = BTreeMap::new();
+
+    // what each asset name is taken by, to catch two files (from different dirs, or renamed by
+    // --trim-prefix) that would overwrite each other
+    let mut names: BTreeMap<String, PathBuf> = BTreeMap::new();
+
+    let mut groups: BTreeMap<String, FormGroup> = BTreeMap::new();
+    for (dir, file) in files {
+        let filename = match opts.name_map.get(&name_map_key(&file)) {
+            Some(name) => name.clone(),
+            None => computed_name(opts, dir, &file)?,
+        };
+        let filename = release_name(opts, filename);
+        // With --normalize-text, the file's contents, read once and hashed from memory. A text
+        // file's copy with the BOM and CRLFs taken out (if it had any) is what's uploaded and
+        // hashed instead of the file on disk; anything else is still streamed from disk.
+        let contents = if opts.normalize_text {
+            std::fs::read(&file).map(Some)
+        } else {
+            Ok(None)
+        };
+        // The file may have been deleted or had its permissions changed since the walk saw it;
+        // don't let one bad file sink the whole upload unless --strict asks us to.
+        let part = contents.and_then(|contents| {
+            let normalized = contents
+                .as_ref()
+                .and_then(|contents| normalize::normalize(contents));
+            let hash = match (&normalized, contents) {
+                _ if !opts.hash_files => None,
+                (Some((normalized, _)), _) => Some(opts.hash_algorithm.hex(normalized)),
+                (None, Some(contents)) => Some(opts.hash_algorithm.hex(&contents)),
+                (None, None) => Some(hash_file(opts.hash_algorithm, &file)?),
+            };
+            let name = match (opts.hash_names, &hash) {
+                (Some(length), Some(hash)) => hashed_name(&filename, hash, length),
+                _ => filename.clone(),
+            };
+            let (part, part_size, changes) = match normalized {
+                Some((contents, changes)) => {
+                    let part_size = contents.len() as u64;
+                    let part = multipart::Part::file_contents(&file, &name, contents);
+                    (part, part_size, changes)
+                }
+                None => {
+                    let (part, part_size) = file_part(&file, &name)?;
+                    (part, part_size, vec![])
+                }
+            };
+            Ok((part, part_size, name, hash, changes))
+        });
+        match part {
+            Ok((part, part_size, name, hash, normalized)) => {
+                if let Some(first) = names.insert(name.clone(), file.clone()) {
+                    return Err(DarkError::DuplicateAsset(
+                        name,
+                        first.display().to_string(),
+                        file.display().to_string(),
+                    ));
+                }
+                if let Some(ref hash) = hash {
+                    hashes.insert(name.clone(), hash.clone());
+                }
+                if let (Some(last_deploy), Some(hash)) = (&opts.last_deploy, &hash) {
+                    if last_deploy.get(&name) == Some(hash) {
+                        unchanged.push((name, part_size));
+                        continue;
+                    }
+                }
+                size += part_size;
+                let group_dir = group_dir(opts, &name);
+                let group = groups.remove(&group_dir).unwrap_or_else(|| FormGroup {
+                    dir: group_dir.clone(),
+                    form: new_form(opts),
+                    files: 0,
+                    size: 0,
+                });
+                let mut form = group
+                    .form
+                    .part(opts.field_name.unwrap_or(&name).to_string(), part);
+                // With --checksums, each file's digest rides along as a text field named after
+                // the asset, so the server can check what it received.
+                if let (true, Some(hash)) = (opts.checksums, &hash) {
+                    form = form.text(
+                        format!("{}.{}", name, opts.hash_algorithm.name()),
+                        hash.clone(),
+                    );
+                }
+                // and with --cache-control, the policy it should be served with
+                if let Some(policy) = opts.cache_control.policy(&name) {
+                    form = form.text(format!("{}.cache-control", name), policy.to_string());
+                }
+                groups.insert(
+                    group_dir,
+                    FormGroup {
+                        form,
+                        files: group.files + 1,
+                        size: group.size + part_size,
+                        ..group
+                    },
+                );
+                entries.push(UploadEntry {
+                    renamed_from: if name != filename {
+                        Some(filename)
+                    } else {
+                        None
+                    },
+                    name,
+                    path: file,
+                    size: part_size,
+                    hash,
+                    normalized,
+                });
+            }
+            Err(error) => {
+                let err = DarkError::FileRead(file.display().to_string(), error);
+                if opts.strict {
+                    return Err(err);
+                }
+                warnings.push(format!("skipped file. {}", err));
+                skipped.push((file.display().to_string(), format!("skipped file. {}", err)));
+            }
+        }
+    }
+
+    if !unchanged.is_empty() && opts.output_format == OutputFormat::Text {
+        println!(
+            "Skipped {} file(s) unchanged since the last deploy (see --since-last-deploy).",
+            unchanged.len()
+        );
+    }
+    if entries.is_empty() && unchanged.is_empty() {
+        return Err(DarkError::NoFilesFound(
+            opts.dirs.join(", "),
+            format!(" (all {} file(s) found couldn't be read)", skipped.len()),
+        ));
+    }
+
+    Ok(FormBody {
+        forms: groups.into_iter().map(|(_, group)| group).collect(),
+        entries,
+        size,
+        skipped,
+        warnings,
+        unchanged,
+        hashes,
+    })
+}
+
+fn upload(
+    opts: &UploadOptions,
+    session: &CookieAndCsrf,
+    form: &multipart::Form,
+) -> Result<(), DarkError> {
+    let requri = format!("{}/api/{}/static_assets", opts.host, opts.canvas);
+    let client = reqwest::Client::builder()
+        .gzip(true)
+        .timeout(None)
+        .build()?;
+    // marked sensitive, so the request --dry-run prints doesn't show them
+    let cookie = secrets::header_value(&format!(
+        "__session={}; Max-Age=604800; domain=darklang.com; path=/; secure; httponly",
+        session.cookie
+    ))
+    .map_err(|_| DarkError::Unknown)?;
+    let csrf = secrets::header_value(&session.csrf).map_err(|_| DarkError::Unknown)?;
+    let request = || {
+        client
+            .post(&requri)
+            .headers(opts.headers.clone())
+            .header("cookie", cookie.clone())
+            .header(opts.csrf_header, csrf.clone())
+            .header(USER_AGENT, format!("{}/{}", PKG_NAME, VERSION))
+    };
+    // the server's errors go into ours, so make sure they don't echo the session back
+    let scrub = |body: String| secrets::scrub(body, &[&session.cookie, &session.csrf]);
+
+    if opts.print_curl {
+        println!("{}", curl_command(opts, &requri, session, form));
+    }
+    if opts.dryrun {
+        println!("{:#?}", request());
+        println!("{:#?}", form);
+        return Ok(());
+    }
+
+    // The Content-Length comes from the sizes the files had when they were gathered; one that
+    // has changed since would make the body the wrong length.
+    let changed_file = || {
+        form.changed_file()
+            .map(|(path, was, now)| DarkError::FileChanged(path.display().to_string(), was, now))
+    };
+    if let Some(err) = changed_file() {
+        return Err(err);
+    }
+    let raw_size = form.len();
+    // With --compress the body is gzipped as it's sent, so we don't know its length up front;
+    // count the bytes as they go by so we can say what it saved.
+    let compressed_size = Arc::new(AtomicU64::new(0));
+    retry::with_retries("the upload", || {
+        // each attempt sends the whole body again, from the start
+        compressed_size.store(0, Ordering::SeqCst);
+        let req = request().header(CONTENT_TYPE, form.content_type());
+        let req = match opts.compress {
+            Some(level) => req
+                .header(CONTENT_ENCODING, "gzip")
+                .body(reqwest::Body::new(CountingReader {
+                    inner: GzEncoder::new(form.reader(), Compression::new(level)),
+                    count: compressed_size.clone(),
+                })),
+            None => req.body(reqwest::Body::sized(form.reader(), raw_size)),
+        };
+        req.send()
+    })
+    .or_else(|error| {
+        // the body's reader fails on a file that changed size while it was being sent
+        if let Some(err) = changed_file() {
+            return Err(err);
+        }
+        if opts.output_format == OutputFormat::Text {
+            println!("Err: {:?}", error);
+        }
+        Err(DarkError::Upload(error))
+    })
+    .and_then(|mut response| match response.status() {
+        StatusCode::FORBIDDEN => {
+            // A stale CSRF token can be rejected with a 403 too; tell it apart from not having
+            // access, which logging in again won't fix.
+            let body = response.text().unwrap_or_default();
+            if is_csrf_rejection(StatusCode::FORBIDDEN, &body) {
+                Err(DarkError::CsrfRejected(scrub(body)))
+            } else {
+                Err(DarkError::Forbidden(format!("canvas {}", opts.canvas)))
+            }
+        }
+        StatusCode::OK => {
+            // json and tsv report the result themselves, and the banner would break their
+            // parsing
+            if opts.output_format == OutputFormat::Text {
+                println!("Upload succeeded!");
+            }
+            if let (Some(id), OutputFormat::Text) = (opts.release, opts.output_format) {
+                // there's no endpoint to point the canvas at a release, so that's left to whatever
+                // serves it
+                println!(
+                    "Uploaded release {} under releases/{}/. It hasn't been activated: Dark's static assets API has no way to mark a release as current, so point what serves the canvas at releases/{}/ to switch to it.",
+                    id, id, id
+                );
+            }
+            // what the server said back is usually only interesting when debugging
+            if opts.show_response || opts.verbosity == Verbosity::Verbose {
+                println!(
+                    "{}",
+                    response
+                        .text()
+                        .unwrap_or_else(|_| String::from("<Undecodable>"))
+                );
+            }
+            // like the other summaries, only for people reading the text output
+            if opts.compress.is_some()
+                && opts.output_format == OutputFormat::Text
+                && opts.verbosity != Verbosity::Quiet
+            {
+                print_compression_summary(
+                    opts.size_units,
+                    raw_size,
+                    compressed_size.load(Ordering::SeqCst),
+                )?;
+            }
+            Ok(())
+        }
+        status if unsupported(status) => Err(DarkError::Unsupported {
+            feature: "static asset uploads",
+            host: opts.host.to_string(),
+        }),
+        _ => {
+            let exec_id = response
+                .headers()
+                .get("X-Darklang-Execution-ID")
+                .and_then(|header| header.to_str().ok())
+                .unwrap_or("<Unknown>")
+                .to_string();
+            let body = response
+                .text()
+                .unwrap_or_else(|_| String::from("<Undecodable>"));
+            if is_csrf_rejection(response.status(), &body) {
+                return Err(DarkError::CsrfRejected(scrub(body)));
+            }
+            Err(DarkError::Non200Response(
+                scrub(body),
+                response.status().as_u16(),
+                exec_id,
+            ))
+        }
+    })
+}
+
+// Whether a response from one of Dark's API endpoints means the instance doesn't have that
+// endpoint at all: a 404, since the routes are Dark's own and don't depend on the canvas. Any
+// endpoint added after static assets (to list, delete or activate them, say) needs the same check,
+// since older instances will keep answering it with a 404.
+fn unsupported(status: StatusCode) -> bool {
+    status == StatusCode::NOT_FOUND
+}
+
+// What Dark answers an upload whose CSRF token doesn't match the session with.
+const CSRF_REJECTION: &str = "Bad CSRF";
+
+// Whether an upload's response is the server rejecting its CSRF token, rather than the session
+// or access to the canvas: Dark's exact answer, with a 401 (its own) or a 403, and nothing looser,
+// since a 403 that merely mentions CSRF shouldn't send the whole upload again.
+fn is_csrf_rejection(status: StatusCode, body: &str) -> bool {
+    (status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN)
+        && body.trim() == CSRF_REJECTION
+}
+
+// Everything that follows a successful upload of `entries`: recording it for
+// --since-last-deploy and --lockfile (merged into the lockfile for --watch's partial uploads),
+// and purging the CDN.
+fn after_upload(
+    opts: &UploadOptions,
+    entries: &[UploadEntry],
+    hashes: &BTreeMap<String, String>,
+    merge: bool,
+) -> Result<(), DarkError> {
+    record_deploy(opts, entries, hashes, merge)?;
+    purge(opts, entries)?;
+    verify_uploaded(opts, entries)
+}
+
+// What follows an upload of `entries`, given how it went: after_upload if it went through; if
+// only some --group-by-directory groups failed, recording the groups that got through (see
+// record_partial_deploy); nothing if it failed outright. `merge` is as for after_upload.
+fn after_outcome(
+    opts: &UploadOptions,
+    entries: &[UploadEntry],
+    hashes: &BTreeMap<String, String>,
+    outcome: Result<(), &DarkError>,
+    merge: bool,
+) -> Result<(), DarkError> {
+    match outcome {
+        Ok(()) => after_upload(opts, entries, hashes, merge),
+        Err(DarkError::GroupsFailed(_, _, _, failed_dirs)) => {
+            record_partial_deploy(opts, entries, hashes, failed_dirs)
+        }
+        Err(_) => Ok(()),
+    }
+}
+
+// Record what was uploaded in the deploy state (for --since-last-deploy) and the --lockfile.
+fn record_deploy<'e, I>(
+    opts: &UploadOptions,
+    entries: I,
+    hashes: &BTreeMap<String, String>,
+    merge: bool,
+) -> Result<(), DarkError>
+where
+    I: IntoIterator<Item = &'e UploadEntry>,
+{
+    if opts.last_deploy.is_some() && !opts.dryrun {
+        state::save(opts.host, &opts.canvas, opts.hash_algorithm, entries);
+    }
+    if let (Some(path), false) = (opts.lockfile, opts.dryrun) {
+        lockfile::write(path, opts.hash_algorithm, hashes, merge)?;
+    }
+    Ok(())
+}
+
+// When some --group-by-directory groups failed (those in `failed_dirs`), record the files in the
+// rest, which did get uploaded, so the next --since-last-deploy run doesn't send them again and
+// the lockfile matches what's deployed. The failed groups' files keep whatever the lockfile said
+// before; files left out as unchanged are still deployed as recorded, wherever they are.
+fn record_partial_deploy(
+    opts: &UploadOptions,
+    entries: &[UploadEntry],
+    hashes: &BTreeMap<String, String>,
+    failed_dirs: &[String],
+) -> Result<(), DarkError> {
+    let failed = |entry: &&UploadEntry| failed_dirs.contains(&group_dir(opts, &entry.name));
+    let failed_names: BTreeSet<&str> = entries
+        .iter()
+        .filter(failed)
+        .map(|entry| entry.name.as_str())
+        .collect();
+    let uploaded_hashes = hashes
+        .iter()
+        .filter(|&(name, _)| !failed_names.contains(name.as_str()))
+        .map(|(name, sha256)| (name.clone(), sha256.clone()))
+        .collect();
+    record_deploy(
+        opts,
+        entries.iter().filter(|entry| !failed(entry)),
+        &uploaded_hashes,
+        true,
+    )
+}
+
+#[derive(Serialize)]
+struct VerificationOutput<'a> {
+    verification: Verification<'a>,
+}
+
+#[derive(Serialize)]
+struct Verification<'a> {
+    uploaded: usize,
+    checked: usize,
+    matched: usize,
+    failed: &'a [FailedVerification<'a>],
+}
+
+#[derive(Serialize)]
+struct FailedVerification<'a> {
+    name: &'a str,
+    error: String,
+}
+
+// With --verify-after-upload, download what was just uploaded from --base-url (every file, or
+// --verify-sample of them spread evenly through the upload), and check each one against the
+// hash of what was sent. This is after the purge, so a CDN that was told to let go of its
+// copies doesn't answer with them.
+fn verify_uploaded(opts: &UploadOptions, entries: &[UploadEntry]) -> Result<(), DarkError> {
+    let base_url = match opts.verify_base_url {
+        Some(url) if !opts.dryrun && !entries.is_empty() => url,
+        _ => return Ok(()),
+    };
+    let sample = opts
+        .verify_sample
+        .map_or(entries.len(), |sample| sample.min(entries.len()));
+    let client = reqwest::Client::new();
+    let mut failed = vec![];
+    for index in 0..sample {
+        let entry = &entries[index * entries.len() / sample];
+        let url = asset_url(base_url, &entry.name)?;
+        // hashed as it was sent (or during the walk), so every entry has its digest by now
+        let local_hash = entry.hash.clone().unwrap_or_default();
+        let error = match download_hash(&client, url.as_str(), opts.hash_algorithm) {
+            Ok(ref remote_hash) if *remote_hash == local_hash => continue,
+            Ok(remote_hash) => format!(
+                "deployed {algorithm} {}, uploaded {algorithm} {}",
+                remote_hash,
+                local_hash,
+                algorithm = opts.hash_algorithm.name()
             ),
+            Err(err) => err.to_string(),
+        };
+        failed.push(FailedVerification {
+            name: &entry.name,
+            error,
+        });
+    }
+
+    match opts.output_format {
+        OutputFormat::Json => {
+            let output = VerificationOutput {
+                verification: Verification {
+                    uploaded: entries.len(),
+                    checked: sample,
+                    matched: sample - failed.len(),
+                    failed: &failed,
+                },
+            };
+            println!("{}", serde_json::to_string(&output).unwrap_or_default());
+        }
+        _ if opts.verbosity != Verbosity::Quiet => println!(
+            "Verified {} of {} uploaded file(s) against {}: {} matched, {} didn't.",
+            sample,
+            entries.len(),
+            base_url,
+            sample - failed.len(),
+            failed.len()
+        ),
+        _ => (),
+    }
+    if failed.is_empty() {
+        return Ok(());
+    }
+    Err(DarkError::VerificationFailed(
+        failed.len(),
+        sample,
+        failed
+            .iter()
+            .map(|failure| format!("{}: {}", failure.name, failure.error))
+            .collect::<Vec<_>>()
+            .join("\n\t"),
+    ))
+}
+
+#[derive(Serialize)]
+struct PurgeRequest<'a> {
+    canvas: &'a str,
+    paths: Vec<&'a str>,
+}
+
+// After a successful upload, ask the CDN in front of the canvas (--purge-url) to drop its cached
+// copies of the uploaded assets. The upload has already happened, so a failed purge is only a
+// warning, unless --purge-required.
+fn purge(opts: &UploadOptions, entries: &[UploadEntry]) -> Result<(), DarkError> {
+    let url = match opts.purge_url {
+        Some(url) if !opts.dryrun && !entries.is_empty() => url,
+        _ => return Ok(()),
+    };
+    let body = PurgeRequest {
+        canvas: &opts.canvas,
+        paths: entries.iter().map(|entry| entry.name.as_str()).collect(),
+    };
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    let result = retry::with_retries("the purge", || {
+        let req = client
+            .post(url)
+            .header(USER_AGENT, format!("{}/{}", PKG_NAME, VERSION))
+            .json(&body);
+        match opts.purge_token {
+            Some(ref token) => req.bearer_auth(token),
+            None => req,
+        }
+        .send()
+    });
+    let outcome = match result {
+        Ok(ref resp) if resp.status().is_success() => Ok(()),
+        Ok(resp) => Err(format!("{} responded {}", url, resp.status())),
+        Err(error) => Err(error.to_string()),
+    };
+    match outcome {
+        Ok(()) => {
+            if opts.verbosity != Verbosity::Quiet && opts.output_format == OutputFormat::Text {
+                println!("Purged {} path(s) via {}.", body.paths.len(), url);
+            }
+            Ok(())
+        }
+        Err(reason) if opts.purge_required => Err(DarkError::Purge(reason)),
+        Err(reason) => {
+            warning::push(format!("the purge failed: {}", reason));
+            Ok(())
+        }
+    }
+}
+
+// Quote `arg` for a POSIX shell.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+// A curl command that sends the same upload, for --print-request-curl. The session cookie and
+// CSRF token are left out unless --unsafe-show-secrets asks for them.
+fn curl_command(
+    opts: &UploadOptions,
+    requri: &str,
+    session: &CookieAndCsrf,
+    form: &multipart::Form,
+) -> String {
+    let (cookie, csrf) = if opts.show_secrets {
+        (session.cookie.as_str(), session.csrf.as_str())
+    } else {
+        (secrets::REDACTED, secrets::REDACTED)
+    };
+    let mut command = vec![
+        format!("curl -X POST {}", shell_quote(requri)),
+        format!(
+            "-H {}",
+            shell_quote(&format!("cookie: __session={}", cookie))
+        ),
+        format!(
+            "-H {}",
+            shell_quote(&format!("{}: {}", opts.csrf_header, csrf))
+        ),
+        format!(
+            "-H {}",
+            shell_quote(&format!("User-Agent: {}/{}", PKG_NAME, VERSION))
+        ),
+    ];
+    for (name, value) in &opts.headers {
+        command.push(format!(
+            "-H {}",
+            shell_quote(&format!(
+                "{}: {}",
+                name,
+                String::from_utf8_lossy(value.as_bytes())
+            ))
+        ));
+    }
+    for (flag, field) in form.curl_fields() {
+        command.push(format!("{} {}", flag, shell_quote(&field)));
+    }
+    let mut command = command.join(" \\\n  ");
+    if opts.compress.is_some() {
+        command.push_str("\n# (--compress gzips the body; curl sends it uncompressed)");
+    }
+    if opts.normalize_text {
+        command.push_str(
+            "\n# (--normalize-text changes the uploaded copies; curl sends the files as is)",
+        );
+    }
+    command
+}
+
+// Counts the bytes read through it, for reporting a body's size once it's been sent.
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.fetch_add(read as u64, Ordering::SeqCst);
+        Ok(read)
+    }
+}
+
+// The current time as an RFC 3339 UTC timestamp, e.g. 2019-12-19T14:03:07Z.
+fn utc_now() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or(0);
+    // Convert days since the epoch to a civil date; see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+// The current time of day (UTC) as HH:MM:SS, for --watch's per-cycle summaries.
+fn timestamp() -> String {
+    format!("{} UTC", &utc_now()[11..19])
+}
+
+// Watch `dirs` and call `on_change` with the files that changed (and the dir each is under),
+// once per debounced batch of changes. Runs until interrupted with Ctrl-C.
+fn watch<'a, F>(dirs: &[&'a str], mut on_change: F) -> Result<(), DarkError>
+where
+    F: FnMut(Vec<(&'a str, PathBuf)>) -> Result<(), DarkError>,
+{
+    use notify::{DebouncedEvent, RecursiveMode, Watcher};
+
+    ctrlc::set_handler(|| {
+        eprintln!("\nStopped watching.");
+        std::process::exit(0);
+    })
+    .map_err(|error| DarkError::Watch(error.to_string()))?;
+
+    // notify reports absolute paths; map them back under their dir so asset names come out the same
+    // as for the initial upload.
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, Duration::from_millis(500))
+        .map_err(|error| DarkError::Watch(error.to_string()))?;
+    let mut roots = vec![];
+    for &dir in dirs {
+        let root = std::fs::canonicalize(dir)?;
+        watcher
+            .watch(&root, RecursiveMode::Recursive)
+            .map_err(|error| DarkError::Watch(error.to_string()))?;
+        roots.push((dir, root));
+    }
+    eprintln!(
+        "Watching {} for changes. Press Ctrl-C to stop.",
+        dirs.join(", ")
+    );
+
+    while let Ok(event) = rx.recv() {
+        let mut changed = BTreeSet::new();
+        let mut removed = BTreeSet::new();
+        // A single save can produce a burst of events across files; take everything that's
+        // already arrived so it goes up as one upload.
+        for event in std::iter::once(event).chain(rx.try_iter()) {
+            match event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Chmod(path) => {
+                    changed.insert(path);
+                }
+                DebouncedEvent::Rename(from, to) => {
+                    removed.insert(from);
+                    changed.insert(to);
+                }
+                DebouncedEvent::Remove(path) => {
+                    removed.insert(path);
+                }
+                DebouncedEvent::Error(error, _) => {
+                    warning::push(format!("error watching {}: {}", dirs.join(", "), error))
+                }
+                _ => (),
+            }
+        }
+
+        // There's no way to delete an asset, so say that a removed file is still deployed rather
+        // than letting it look handled. (The deploy state and the lockfile keep it too, since
+        // it's still on the canvas.)
+        for path in removed.into_iter().filter(|path| !path.exists()) {
+            if let Some(&(dir, ref root)) =
+                roots.iter().find(|&&(_, ref root)| path.starts_with(root))
+            {
+                let relative = path.strip_prefix(root).unwrap_or(&path);
+                warning::push(format!(
+                    "{} was removed or moved away, but it's still deployed: Dark's static assets API can't delete assets, so removals aren't uploaded.",
+                    Path::new(dir).join(relative).display()
+                ));
+            }
+        }
+
+        let files: Vec<(&str, PathBuf)> = changed
+            .into_iter()
+            .filter(|path| path.is_file())
+            .filter_map(|path| {
+                roots.iter().find_map(|&(dir, ref root)| {
+                    path.strip_prefix(root)
+                        .ok()
+                        .map(|relative| (dir, Path::new(dir).join(relative)))
+                })
+            })
+            .collect();
+        if files.is_empty() {
+            print_warnings(&warning::take());
+            continue;
+        }
+
+        // Keep watching even if one cycle fails; the next save gets another chance.
+        if let Err(err) = on_change(files) {
+            eprintln!("[{}] {}", timestamp(), err);
+        }
+    }
+
+    Ok(())
+}
+
+// Log in, then walk the dirs and upload them, recording what went into the form in `log`.
+fn login_and_upload(
+    opts: &UploadOptions,
+    user: String,
+    password: String,
+    log: &mut UploadLog,
+) -> Result<CookieAndCsrf, DarkError> {
+    let cached = if opts.use_cache && opts.auth_command.is_none() {
+        cache::load(opts.host, &user)
+    } else {
+        None
+    };
+    let from_cache = cached.is_some();
+    // Log in before walking, so bad credentials fail before a big tree is scanned and hashed.
+    // With --no-fail-fast-auth a failed login is held back until the files have been listed.
+    let started = Instant::now();
+    let session = match cached {
+        Some(session) => {
+            if opts.verbosity != Verbosity::Quiet {
+                eprintln!("Using cached session.");
+            }
+            Ok(session)
+        }
+        None => log_in(opts, &user, password.clone()),
+    };
+    log.timings.auth = Some(started.elapsed());
+    let session = match session {
+        Err(err) if opts.fail_fast_auth => return Err(err),
+        session => session,
+    };
+
+    let started = Instant::now();
+    let FormBody {
+        forms,
+        entries,
+        size,
+        skipped,
+        mut warnings,
+        unchanged,
+        hashes,
+    } = match opts.stdin_name {
+        Some(name) => form_from_stdin(opts, name)?,
+        None => form_body(opts)?,
+    };
+    log.timings.walk = Some(started.elapsed());
+
+    // only --since-last-deploy can leave nothing to upload without it being an error
+    let nothing_changed = entries.is_empty();
+    if !(nothing_changed && opts.output_format == OutputFormat::Text) {
+        print_files(opts, &entries, size, &skipped)?;
+    }
+    if opts.size_breakdown {
+        print_size_breakdown(opts, &entries, size)?;
+    }
+    if opts.verbosity == Verbosity::Verbose && opts.output_format == OutputFormat::Text {
+        print_cache_policies(opts, &entries);
+    }
+    let session = session?;
+    if let Some(manifest) = opts.manifest {
+        write_name_manifest(manifest, &entries, false)?;
+    }
+    let checked = check_lockfile(opts, &hashes, &mut warnings);
+    // and whatever came up logging in (a retry, say), so --warnings-as-errors sees those too
+    warnings.extend(warning::take());
+    log.entries = entries;
+    log.warnings = warnings;
+    log.hashes = hashes;
+    log.skipped = skipped;
+    log.unchanged = unchanged;
+    checked?;
+    check_warnings(opts, &log.warnings)?;
+    if nothing_changed {
+        if opts.verbosity != Verbosity::Quiet && opts.output_format == OutputFormat::Text {
+            println!(
+                "Nothing changed since the last deploy to {} ({} file(s) unchanged).",
+                opts.canvas,
+                log.unchanged.len()
+            );
+        }
+        return Ok(session);
+    }
+    confirm_production(opts, log.entries.len(), size)?;
+    let started = Instant::now();
+    let mut done = BTreeSet::new();
+    let result = match upload_forms(opts, &session, &forms, &mut done) {
+        // The cached session looked young enough, but the server has already expired it (or our
+        // clock is off); drop it and try once more with a fresh login.
+        Err(DarkError::Non200Response(_, 401, _)) if from_cache => {
+            if opts.verbosity != Verbosity::Quiet {
+                eprintln!("The cached session was rejected; logging in again.");
+            }
+            cache::forget(opts.host, &user);
+            log_in(opts, &user, password).and_then(|session| {
+                upload_forms(opts, &session, &forms, &mut done).map(|_| session)
+            })
+        }
+        // The session's CSRF token has been rotated out from under us; a fresh login comes with
+        // a new one.
+        Err(DarkError::CsrfRejected(_)) => {
+            if opts.verbosity != Verbosity::Quiet {
+                eprintln!("The server rejected the CSRF token; logging in again.");
+            }
+            if from_cache {
+                cache::forget(opts.host, &user);
+            }
+            log_in(opts, &user, password).and_then(|session| {
+                upload_forms(opts, &session, &forms, &mut done).map(|_| session)
+            })
+        }
+        result => result.map(|_| session),
+    };
+    log.timings.upload = Some(started.elapsed());
+    fill_sent_hashes(opts, &forms, &mut log.entries);
+    result
+}
+
+// Ask before uploading to production (the default host), unless --yes or --dry-run. Dev and
+// self-hosted hosts aren't asked about, and neither is a run without a terminal to answer on
+// (CI, or stdin piped for --stdin-name).
+fn confirm_production(opts: &UploadOptions, files: usize, size: u64) -> Result<(), DarkError> {
+    if opts.assume_yes
+        || opts.dryrun
+        || opts.host != DEFAULT_HOST
+        || !atty::is(atty::Stream::Stdin)
+        || !atty::is(atty::Stream::Stdout)
+    {
+        return Ok(());
+    }
+    print!(
+        "Upload {} file(s) ({}) to canvas {} on {}? [y/N] ",
+        files,
+        opts.size_units.format(size)?,
+        opts.canvas,
+        opts.host
+    );
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(DarkError::Cancelled),
+    }
+}
+
+// Compare the files about to be uploaded against the --lockfile, adding a warning for each one
+// that differs; with --frozen, any difference (or having no lockfile, or one made with another
+// --hash-algorithm) is an error.
+fn check_lockfile(
+    opts: &UploadOptions,
+    hashes: &BTreeMap<String, String>,
+    warnings: &mut Vec<String>,
+) -> Result<(), DarkError> {
+    let path = match opts.lockfile {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    match lockfile::read(path)? {
+        Some(ref locked) if locked.hash_algorithm != opts.hash_algorithm.name() && opts.frozen => {
+            Err(DarkError::LockfileHashAlgorithm(
+                path.to_string(),
+                locked.hash_algorithm.clone(),
+                opts.hash_algorithm.name().to_string(),
+            ))
+        }
+        // a different algorithm's hashes would all look changed, so there's nothing to check
+        Some(ref locked) if locked.hash_algorithm != opts.hash_algorithm.name() => {
+            warnings.push(format!(
+                "{} records {} hashes, not {}, so nothing was checked against it; a successful upload rewrites it.",
+                path,
+                locked.hash_algorithm,
+                opts.hash_algorithm.name()
+            ));
+            Ok(())
+        }
+        Some(locked) => {
+            let drift = lockfile::drift(path, &locked.files, hashes);
+            let count = drift.len();
+            warnings.extend(drift);
+            if opts.frozen && count > 0 {
+                return Err(DarkError::LockfileDrift(path.to_string(), count));
+            }
+            Ok(())
+        }
+        None if opts.frozen => Err(DarkError::MissingLockfile(path.to_string())),
+        None => Ok(()),
+    }
+}
+
+#[derive(Serialize)]
+struct TimingsOutput {
+    // seconds per phase
+    timings: BTreeMap<&'static str, f64>,
+}
+
+// What happened to one file, in the --output-format json results.
+#[derive(Serialize)]
+struct FileResult<'a> {
+    name: &'a str,
+    size: Option<u64>,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ResultsOutput<'a> {
+    results: Vec<FileResult<'a>>,
+}
+
+// Under --output-format json, once the run is over, a {"results": [...]} line saying what became
+// of each file: uploaded (or would_upload, with --dry-run), unchanged (--since-last-deploy),
+// skipped (unreadable), failed (the upload was rejected or didn't get through), or not_uploaded
+// (the run stopped before uploading, say for --frozen). The upload is a single request, so every
+// file in it shares its outcome.
+fn print_results(opts: &UploadOptions, log: &UploadLog, outcome: Result<(), &DarkError>) {
+    if opts.output_format != OutputFormat::Json {
+        return;
+    }
+    let (status, error) = match outcome {
+        Ok(()) if opts.dryrun => ("would_upload", None),
+        Ok(()) => ("uploaded", None),
+        Err(err) if log.timings.upload.is_some() => ("failed", Some(err.to_string())),
+        Err(err) => ("not_uploaded", Some(err.to_string())),
+    };
+    // with --group-by-directory, the groups that failed; the files in the others got through
+    let failed_groups = match outcome {
+        Err(DarkError::GroupsFailed(_, _, _, dirs)) => Some(dirs),
+        _ => None,
+    };
+    let uploaded = log.entries.iter().map(|entry| match failed_groups {
+        Some(dirs) if !dirs.contains(&group_dir(opts, &entry.name)) => FileResult {
+            name: &entry.name,
+            size: Some(entry.size),
+            status: "uploaded",
+            error: None,
+        },
+        _ => FileResult {
+            name: &entry.name,
+            size: Some(entry.size),
+            status,
+            error: error.clone(),
+        },
+    });
+    let unchanged = log.unchanged.iter().map(|&(ref name, size)| FileResult {
+        name,
+        size: Some(size),
+        status: "unchanged",
+        error: None,
+    });
+    let skipped = log
+        .skipped
+        .iter()
+        .map(|&(ref path, ref reason)| FileResult {
+            name: path,
+            size: None,
+            status: "skipped",
+            error: Some(reason.clone()),
+        });
+    let output = ResultsOutput {
+        results: uploaded.chain(unchanged).chain(skipped).collect(),
+    };
+    println!("{}", serde_json::to_string(&output).unwrap_or_default());
+}
+
+// Print how long each phase took, for --timings: a table, or under --output-format json, a
+// {"timings": {...}} line of its own after the file listing.
+fn print_timings(opts: &UploadOptions, timings: &Timings) {
+    let phases: Vec<(&'static str, Duration)> = vec![
+        ("auth", timings.auth),
+        ("walk", timings.walk),
+        ("upload", timings.upload),
+    ]
+    .into_iter()
+    .filter_map(|(phase, duration)| duration.map(|duration| (phase, duration)))
+    .collect();
+    match opts.output_format {
+        OutputFormat::Json => {
+            let output = TimingsOutput {
+                timings: phases
+                    .into_iter()
+                    .map(|(phase, duration)| (phase, duration.as_millis() as f64 / 1000.0))
+                    .collect(),
+            };
+            println!("{}", serde_json::to_string(&output).unwrap_or_default());
+        }
+        OutputFormat::Tsv => {
+            for (phase, duration) in phases {
+                println!("{}\t{:.3}", phase, duration.as_secs_f64());
+            }
+        }
+        OutputFormat::Text => {
+            println!("Timings:");
+            for (phase, duration) in phases {
+                println!("  {:<8}{:.2}s", phase, duration.as_secs_f64());
+            }
+        }
+    }
+}
+
+// With --warnings-as-errors, refuse to upload after anything produced a warning.
+fn check_warnings(opts: &UploadOptions, warnings: &[String]) -> Result<(), DarkError> {
+    if opts.warnings_as_errors && !warnings.is_empty() {
+        return Err(DarkError::WarningsAsErrors(warnings.len()));
+    }
+    Ok(())
+}
+
+// With --warnings-as-errors, fail a run whose upload went through but then produced warnings (a
+// retried request, a failed purge): too late not to upload, but not too late to exit nonzero.
+fn check_late_warnings(opts: &UploadOptions, warnings: &[String]) -> Result<(), DarkError> {
+    if opts.warnings_as_errors && !warnings.is_empty() {
+        return Err(DarkError::UploadWarnings(warnings.len()));
+    }
+    Ok(())
+}
+
+// Print the run's warnings together, so one scrolled past in a long file list isn't missed.
+fn print_warnings(warnings: &[String]) {
+    if warnings.is_empty() {
+        return;
+    }
+    eprintln!("\nWarnings ({}):", warnings.len());
+    for warning in warnings {
+        eprintln!("  {}", warning);
+    }
+}
+
+// Log in for a new session, saving it to the session cache unless --no-cache. With
+// --auth-command, the helper hands us the session instead (and keeps track of its lifetime).
+fn log_in(opts: &UploadOptions, user: &str, password: String) -> Result<CookieAndCsrf, DarkError> {
+    if let Some(command) = opts.auth_command {
+        return run_auth_command(command);
+    }
+    let session = cookie_and_csrf(
+        &opts.login_url,
+        user.to_string(),
+        password,
+        opts.verbosity,
+        &opts.headers,
+    )?;
+    if opts.use_cache {
+        cache::save(opts.host, user, &session);
+    }
+    Ok(session)
+}
+
+// Run the --auth-command helper through the shell, like git's credential helpers, and parse the
+// session it prints. Its stderr is left alone, so it can prompt or explain itself.
+fn run_auth_command(command: &str) -> Result<CookieAndCsrf, DarkError> {
+    let failed = |detail: String| DarkError::AuthCommand(command.to_string(), detail);
+    let mut shell = if cfg!(windows) {
+        let mut shell = std::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = std::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let output = shell
+        .arg(command)
+        .stdin(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .map_err(|err| failed(format!("couldn't be run ({})", err)))?;
+    if !output.status.success() {
+        return Err(failed(format!("failed ({})", output.status)));
+    }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|err| failed(format!("didn't print a session ({})", err)))
+}
+
+// Above this many files, we print a count rather than every file (unless --list-files).
+const LIST_FILES_THRESHOLD: usize = 50;
+
+fn print_file_list(opts: &UploadOptions, entries: &[UploadEntry]) {
+    if opts
+        .list_files
+        .unwrap_or(entries.len() < LIST_FILES_THRESHOLD)
+    {
+        for entry in entries {
+            if entry.normalized.is_empty() {
+                println!("File: {}", entry.name);
+            } else {
+                println!(
+                    "File: {} (normalized: {})",
+                    entry.name,
+                    entry.normalized.join(", ")
+                );
+            }
+        }
+    } else {
+        println!("{} files.", entries.len());
+    }
+}
+
+// A file as printed by --output-format json.
+#[derive(Serialize)]
+struct ListedFile<'a> {
+    path: &'a str,
+    size: Option<u64>,
+    status: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    normalized: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct FileListing<'a> {
+    files: Vec<ListedFile<'a>>,
+    total_size: u64,
+    skipped: usize,
+}
+
+// Print the files going into the upload and their total size, in the --output-format. JSON is
+// a single line, so --watch prints one object per upload; TSV is a `path\tsize\tstatus` row
+// per file, with no summary row (the total is the sum of the size column).
+fn print_files(
+    opts: &UploadOptions,
+    entries: &[UploadEntry],
+    size: u64,
+    skipped: &[(String, String)],
+) -> Result<(), DarkError> {
+    let listed = entries
+        .iter()
+        .map(|entry| ListedFile {
+            path: &entry.name,
+            size: Some(entry.size),
+            status: "upload",
+            normalized: entry.normalized.clone(),
+        })
+        .chain(skipped.iter().map(|(path, _)| ListedFile {
+            path,
+            size: None,
+            status: "skipped",
+            normalized: vec![],
+        }));
+    match opts.output_format {
+        OutputFormat::Text => {
+            print_file_list(opts, entries);
+            print_upload_summary(opts.size_units, size, skipped.len())?;
+        }
+        OutputFormat::Json => {
+            let listing = FileListing {
+                files: listed.collect(),
+                total_size: size,
+                skipped: skipped.len(),
+            };
+            println!("{}", serde_json::to_string(&listing).unwrap_or_default());
+        }
+        OutputFormat::Tsv => {
+            for file in listed {
+                let size = file.size.map(|size| size.to_string());
+                println!(
+                    "{}\t{}\t{}",
+                    file.path,
+                    size.as_ref().map_or("-", String::as_str),
+                    file.status
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ExcludedFile<'a> {
+    path: &'a str,
+    reason: &'a str,
+}
+
+#[derive(Serialize)]
+struct ExcludedListing<'a> {
+    excluded: Vec<ExcludedFile<'a>>,
+}
+
+// With --list-excluded, print the files the walk found but left out, and why. In TSV these are
+// rows with an "excluded" status; in JSON, an object of their own ahead of the file list.
+fn print_excluded(opts: &UploadOptions, excluded: &[filter::Excluded]) {
+    match opts.output_format {
+        OutputFormat::Text => {
+            for file in excluded {
+                println!("Excluded: {} ({})", file.path, file.reason);
+            }
+        }
+        OutputFormat::Json => {
+            let listing = ExcludedListing {
+                excluded: excluded
+                    .iter()
+                    .map(|file| ExcludedFile {
+                        path: &file.path,
+                        reason: &file.reason,
+                    })
+                    .collect(),
+            };
+            println!("{}", serde_json::to_string(&listing).unwrap_or_default());
+        }
+        OutputFormat::Tsv => {
+            for file in excluded {
+                println!("{}\t-\texcluded", file.path);
+            }
+        }
+    }
+}
+
+fn print_upload_summary(units: SizeUnits, size: u64, skipped: usize) -> Result<(), DarkError> {
+    if skipped > 0 {
+        println!(
+            "Going to attempt to upload files totalling {} ({} unreadable file(s) skipped).",
+            units.format(size)?,
+            skipped
+        );
+    } else {
+        println!(
+            "Going to attempt to upload files totalling {}.",
+            units.format(size)?
+        );
+    }
+    Ok(())
+}
+
+// With --size-breakdown, the upload's total size by file extension, biggest first. It goes to
+// stderr under --output-format json/tsv, so it doesn't get mixed into what scripts parse.
+fn print_size_breakdown(
+    opts: &UploadOptions,
+    entries: &[UploadEntry],
+    total: u64,
+) -> Result<(), DarkError> {
+    let mut by_extension: BTreeMap<String, (u64, usize)> = BTreeMap::new();
+    for entry in entries {
+        let file = entry.name.rsplit('/').next().unwrap_or(&entry.name);
+        let extension = match file.rfind('.') {
+            // a leading dot (.htaccess) isn't an extension
+            Some(dot) if dot > 0 => format!(".{}", file[dot + 1..].to_lowercase()),
+            _ => "(none)".to_string(),
+        };
+        let totals = by_extension.entry(extension).or_insert((0, 0));
+        totals.0 += entry.size;
+        totals.1 += 1;
+    }
+    let mut by_size: Vec<(String, (u64, usize))> = by_extension.into_iter().collect();
+    by_size.sort_by(|a, b| (b.1).0.cmp(&(a.1).0));
+
+    let mut lines = vec!["Size by file type:".to_string()];
+    for (extension, (size, count)) in by_size {
+        lines.push(format!(
+            "  {:<12} {:>10}  {:>3}%  {} file(s)",
+            extension,
+            opts.size_units.format(size)?,
+            if total > 0 { size * 100 / total } else { 0 },
+            count
+        ));
+    }
+    if opts.output_format == OutputFormat::Text {
+        println!("{}", lines.join("\n"));
+    } else {
+        eprintln!("{}", lines.join("\n"));
+    }
+    Ok(())
+}
+
+// At -v, the Cache-Control each kind of file is sent with, so the effect of --cache-preset and
+// the --cache-control rules can be checked before trusting a deploy to them.
+fn print_cache_policies(opts: &UploadOptions, entries: &[UploadEntry]) {
+    if opts.cache_control.is_empty() {
+        return;
+    }
+    let mut by_type: BTreeMap<String, BTreeSet<&str>> = BTreeMap::new();
+    for entry in entries {
+        by_type
+            .entry(cache_control::file_type(&entry.name))
+            .or_insert_with(BTreeSet::new)
+            .insert(opts.cache_control.policy(&entry.name).unwrap_or("(none)"));
+    }
+    println!("Cache-Control by file type:");
+    for (file_type, policies) in by_type {
+        for policy in policies {
+            println!("  {:<12} {}", file_type, policy);
+        }
+    }
+}
+
+fn print_compression_summary(
+    units: SizeUnits,
+    raw_size: u64,
+    compressed_size: u64,
+) -> Result<(), DarkError> {
+    println!(
+        "Compressed {} to {} ({}%).",
+        units.format(raw_size)?,
+        units.format(compressed_size)?,
+        if raw_size > 0 {
+            compressed_size * 100 / raw_size
+        } else {
+            100
+        }
+    );
+    Ok(())
+}
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const PKG_NAME: &str = env!("CARGO_PKG_NAME");
+// Name of the header the server reads the CSRF token from; overridable with --csrf-header in
+// case a server renames it.
+const CSRF_HEADER: &str = "x-csrf-token";
+const DEFAULT_HOST: &str = "https://darklang.com";
+// Where the username and password are exchanged for a session, for uploads to DEFAULT_HOST.
+const LOGIN_URL: &str = "https://login.darklang.com/dark-cli";
+
+// Where to log in for an upload to `host`: --login-url, then login_url in the host's
+// [hosts."<url>"] table, then LOGIN_URL for darklang.com, and <host>/dark-cli for any other
+// host, so credentials only ever go to the instance being deployed to.
+fn login_url(flag: Option<&str>, settings: &config::Settings, host: &str) -> String {
+    match flag
+        .map(str::to_string)
+        .or_else(|| settings.login_url(host))
+    {
+        Some(url) => url,
+        None if host == DEFAULT_HOST => LOGIN_URL.to_string(),
+        None => format!("{}/dark-cli", host),
+    }
+}
+
+// $DARK_HOST, for pointing every command at a self-hosted instance.
+fn env_host() -> Option<String> {
+    env::var("DARK_HOST")
+        .ok()
+        .filter(|host| !host.is_empty())
+        .map(|host| host.trim_end_matches('/').to_string())
+}
+
+// The headers the tool sets itself, which --header may not override.
+const MANAGED_HEADERS: &[&str] = &[
+    "authorization",
+    "content-encoding",
+    "content-length",
+    "content-type",
+    "cookie",
+    "host",
+    "transfer-encoding",
+    "user-agent",
+];
+
+// Parse the --header values ("Name: Value") into headers for the login and upload requests,
+// rejecting malformed ones and any the tool manages itself (including the CSRF header).
+fn parse_headers(values: &[&str], csrf_header: &str) -> Result<HeaderMap, DarkError> {
+    let invalid = |reason: String| DarkError::InvalidArgument("header".to_string(), reason);
+    let mut headers = HeaderMap::new();
+    for value in values {
+        let colon = value
+            .find(':')
+            .ok_or_else(|| invalid(format!("{:?} isn't of the form 'Name: Value'", value)))?;
+        let name = HeaderName::from_bytes(value[..colon].trim().as_bytes())
+            .map_err(|_| invalid(format!("{:?} isn't a valid header name", &value[..colon])))?;
+        if MANAGED_HEADERS.contains(&name.as_str()) || name.as_str() == csrf_header.to_lowercase() {
+            return Err(invalid(format!("{} is set by dark-cli itself", name)));
+        }
+        let header_value = secrets::header_value(value[colon + 1..].trim())
+            .map_err(|_| invalid(format!("{:?} has an invalid value", value)))?;
+        headers.append(name, header_value);
+    }
+    Ok(headers)
+}
+
+// Whether $DARK_NO_CACHE is set to anything but empty or 0.
+fn env_no_cache() -> bool {
+    env::var("DARK_NO_CACHE")
+        .map(|value| !value.is_empty() && value != "0")
+        .unwrap_or(false)
+}
+
+// The flags for an upload, shared by the top-level invocation and `dark deploy`.
+fn upload_args<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app
+        .arg(
+            Arg::with_name("user")
+                .long("user")
+                .required(false)
+                .takes_value(true)
+                .help("Your dark username"),
+        )
+        .arg(
+            Arg::with_name("password")
+                .long("password")
+                .required(false)
+                .takes_value(true)
+                .requires("user")
+                .help("Your dark password"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .required(false)
+                .takes_value(false)
+                .help("Don't upload to canvas, just print request"),
+        )
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .required(false)
+                .takes_value(true)
+                .value_name("url")
+                .help("The Darklang instance to upload to (e.g. a self-hosted one)"),
+        )
+        .arg(
+            Arg::with_name("login-url")
+                .long("login-url")
+                .required(false)
+                .takes_value(true)
+                .value_name("url")
+                .help("Where to log in [default: https://login.darklang.com/dark-cli for darklang.com, <host>/dark-cli otherwise]"),
+        )
+        .arg(
+            Arg::with_name("dev")
+                .long("dev")
+                .required(false)
+                .takes_value(false)
+                .help("Run against localhost - debug only."),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .required(false)
+                .takes_value(false)
+                .help("Abort the upload if any file can't be read, rather than skipping it"),
+        )
+        .arg(
+            Arg::with_name("csrf-header")
+                .long("csrf-header")
+                .required(false)
+                .takes_value(true)
+                .hidden(true)
+                .help("Advanced, for compatibility only: name of the header to send the CSRF token in (default: x-csrf-token)"),
+        )
+        .arg(
+            Arg::with_name("field-name")
+                .long("field-name")
+                .required(false)
+                .takes_value(true)
+                .help("Use this multipart field name for every file (e.g. files[]), rather than each file's name"),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .long("watch")
+                .required(false)
+                .takes_value(false)
+                .help("After uploading, keep watching and re-upload files as they change"),
+        )
+        .arg(
+            Arg::with_name("checksums")
+                .long("checksums")
+                .required(false)
+                .takes_value(false)
+                .help("Send each file's hash alongside it (as <name>.sha256, or <name>.<algorithm> with --hash-algorithm) so the server can verify it"),
+        )
+        .arg(
+            Arg::with_name("cache-control")
+                .long("cache-control")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("[glob=]policy")
+                .help("Ask the server to serve files with this Cache-Control (sent as <name>.cache-control), e.g. 'public, max-age=3600' for every file or '*.js=public, max-age=31536000, immutable' for those matching the glob; can be repeated, and the last matching rule wins"),
+        )
+        .arg(
+            Arg::with_name("cache-preset")
+                .long("cache-preset")
+                .required(false)
+                .takes_value(false)
+                .help("Cache fonts, images and files with a content hash in their name for a year (immutable), and .html for five minutes; --cache-control rules override these"),
+        )
+        .arg(
+            Arg::with_name("warnings-as-errors")
+                .long("warnings-as-errors")
+                .required(false)
+                .takes_value(false)
+                .help("Don't upload (and exit nonzero) if anything produced a warning, like a file that couldn't be read; a warning after the upload, like a failed purge, still exits nonzero"),
+        )
+        .arg(
+            Arg::with_name("sanitize-names")
+                .long("sanitize-names")
+                .required(false)
+                .takes_value(false)
+                .help("Make asset names URL-safe: spaces become dashes, ?, # and control characters are dropped, and repeated slashes collapse"),
+        )
+        .arg(
+            Arg::with_name("normalize-text")
+                .long("normalize-text")
+                .required(false)
+                .takes_value(false)
+                .help("Strip a UTF-8 BOM and convert CRLF to LF in text files before uploading (the files on disk are left alone)"),
+        )
+        .arg(
+            Arg::with_name("name-from-hash")
+                .long("name-from-hash")
+                .required(false)
+                .takes_value(false)
+                .help("Insert a hash of each file's contents into its name (app.js becomes app.1a2b3c4d.js)"),
+        )
+        .arg(
+            Arg::with_name("hash-length")
+                .long("hash-length")
+                .required(false)
+                .takes_value(true)
+                .default_value("8")
+                .help("How many characters of the hash --name-from-hash inserts"),
+        )
+        .arg(
+            Arg::with_name("hash-algorithm")
+                .long("hash-algorithm")
+                .required(false)
+                .takes_value(true)
+                .value_name("algorithm")
+                .possible_values(hash::NAMES)
+                .default_value("sha256")
+                .help("How files are hashed, for --name-from-hash, --checksums, --lockfile, --since-last-deploy, verification and the manifest; blake3 is much faster on large trees"),
+        )
+        .arg(
+            Arg::with_name("manifest")
+                .long("manifest")
+                .required(false)
+                .takes_value(true)
+                .value_name("path")
+                .requires("name-from-hash")
+                .help("Write the original -> hashed name mapping from --name-from-hash to this path as JSON"),
+        )
+        .arg(
+            Arg::with_name("trim-prefix")
+                .long("trim-prefix")
+                .required(false)
+                .takes_value(true)
+                .value_name("path")
+                .help("Strip these leading directories from asset names (with static, static/js/app.js uploads as js/app.js); names without them are left as they are, or are an error with --strict"),
+        )
+        .arg(
+            Arg::with_name("release")
+                .long("release")
+                .required(false)
+                .takes_value(true)
+                .value_name("id")
+                .help("Upload everything under releases/<id>/ (e.g. --release v1.2.3 uploads js/app.js as releases/v1.2.3/js/app.js), so earlier releases stay in place to go back to"),
+        )
+        .arg(
+            Arg::with_name("list-files")
+                .long("list-files")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("no-list-files")
+                .help("Print every file being uploaded (the default for fewer than 50 files)"),
+        )
+        .arg(
+            Arg::with_name("no-list-files")
+                .long("no-list-files")
+                .required(false)
+                .takes_value(false)
+                .help("Print just the number of files being uploaded, not each one"),
+        )
+        .arg(
+            Arg::with_name("lockfile")
+                .long("lockfile")
+                .required(false)
+                .takes_value(false)
+                .help("Warn about files that differ from .dark-assets.json, and rewrite it with every asset's hash after a successful upload"),
+        )
+        .arg(
+            Arg::with_name("frozen")
+                .long("frozen")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("watch")
+                .help("Like --lockfile, but fail if any file differs from .dark-assets.json (e.g. `dark manifest --frozen` in CI)"),
+        )
+        .arg(
+            Arg::with_name("auth-command")
+                .long("auth-command")
+                .required(false)
+                .takes_value(true)
+                .value_name("cmd")
+                .conflicts_with_all(&["user", "password"])
+                .help("Get the session from this command (run by the shell) instead of logging in: it should print {\"sessionKey\": ..., \"csrfToken\": ...}, like the login endpoint"),
+        )
+        .arg(
+            Arg::with_name("fail-fast-auth")
+                .long("fail-fast-auth")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("no-fail-fast-auth")
+                .help("Stop as soon as logging in fails, before walking any files (the default)"),
+        )
+        .arg(
+            Arg::with_name("no-fail-fast-auth")
+                .long("no-fail-fast-auth")
+                .required(false)
+                .takes_value(false)
+                .help("If logging in fails, still walk and list the files (e.g. to preview a --dry-run) before exiting with the login error"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .required(false)
+                .takes_value(true)
+                .help("Use the settings from [profile.<name>] in the config files"),
+        )
+        .arg(
+            Arg::with_name("no-system-config")
+                .long("no-system-config")
+                .required(false)
+                .takes_value(false)
+                .help("Don't read the machine-wide config file, /etc/dark/config.toml"),
+        )
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .required(false)
+                .takes_value(true)
+                .value_name("path")
+                .help("Write a report of the run (files, hashes, outcome) to this path; JSON if it ends in .json, text otherwise"),
+        )
+        .arg(
+            Arg::with_name("compress")
+                .long("compress")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("no-compress")
+                .help("Gzip the upload (sent with Content-Encoding: gzip); saves bandwidth on text-heavy sites"),
+        )
+        .arg(
+            Arg::with_name("no-compress")
+                .long("no-compress")
+                .required(false)
+                .takes_value(false)
+                .help("Send the upload uncompressed (the default)"),
+        )
+        .arg(
+            Arg::with_name("compress-level")
+                .long("compress-level")
+                .required(false)
+                .takes_value(true)
+                .default_value("6")
+                .help("The gzip level for --compress, from 0 (fastest) to 9 (smallest)"),
+        )
+        .arg(
+            Arg::with_name("output-format")
+                .long("output-format")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&["text", "json", "tsv", "yaml"])
+                .default_value("text")
+                .help("How to print the file list and summary; tsv prints a path<TAB>size<TAB>status row per file, and yaml is for dark manifest and --print-config"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .required(false)
+                .takes_value(false)
+                .help("Shorthand for --output-format json"),
+        )
+        .arg(
+            Arg::with_name("no-cache")
+                .long("no-cache")
+                .required(false)
+                .takes_value(false)
+                .help("Log in afresh rather than reusing a cached session, and don't save this one (also set by $DARK_NO_CACHE=1, or cache = false in a config file)"),
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("glob")
+                .help("Don't upload files whose asset name matches this glob (e.g. '*.map' or 'drafts/**'); can be repeated"),
+        )
+        .arg(
+            Arg::with_name("exclude-from")
+                .long("exclude-from")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("path")
+                .help("Exclude the globs listed in this file, one per line (blank lines and # comments are ignored); can be repeated"),
+        )
+        .arg(
+            Arg::with_name("min-file-size")
+                .long("min-file-size")
+                .required(false)
+                .takes_value(true)
+                .value_name("bytes")
+                .default_value("0")
+                .help("Don't upload files smaller than this many bytes"),
+        )
+        .arg(
+            Arg::with_name("skip-empty")
+                .long("skip-empty")
+                .required(false)
+                .takes_value(false)
+                .help("Don't upload empty files (placeholders such as .keep)"),
+        )
+        .arg(
+            Arg::with_name("timings")
+                .long("timings")
+                .required(false)
+                .takes_value(false)
+                .help("Print how long logging in, walking (and hashing) the files, and uploading each took"),
+        )
+        .arg(
+            Arg::with_name("map-names-from")
+                .long("map-names-from")
+                .required(false)
+                .takes_value(true)
+                .value_name("file")
+                .help("Upload the files listed in this TOML (or .json) file under other names: \"local/path\" = \"deployed/name\" per line"),
+        )
+        .arg(
+            Arg::with_name("allow-external-links")
+                .long("allow-external-links")
+                .required(false)
+                .takes_value(false)
+                .help("Follow symlinks that point outside the dirs being uploaded (by default they're left out, with a warning)"),
+        )
+        .arg(
+            Arg::with_name("only")
+                .long("only")
+                .required(false)
+                .takes_value(true)
+                .value_name("glob")
+                .help("Only upload the files under the dirs matching this glob, e.g. --only js/app.js to deploy just the file you changed"),
+        )
+        .arg(
+            Arg::with_name("ignore-case")
+                .long("ignore-case")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("case-sensitive-globs")
+                .help("Match --exclude, --only and glob paths regardless of case, so *.JS matches app.js (the default on macOS and Windows)"),
+        )
+        .arg(
+            Arg::with_name("case-sensitive-globs")
+                .long("case-sensitive-globs")
+                .required(false)
+                .takes_value(false)
+                .help("Match --exclude, --only and glob paths case-sensitively (the default everywhere but macOS and Windows)"),
+        )
+        .arg(
+            Arg::with_name("allow-empty")
+                .long("allow-empty")
+                .required(false)
+                .takes_value(false)
+                .help("Don't fail when a glob path (e.g. 'dist/**/*.css') or --only matches no files"),
+        )
+        .arg(
+            Arg::with_name("since-last-deploy")
+                .long("since-last-deploy")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("stdin-name")
+                .help("Only upload files that changed since the last successful --since-last-deploy to this canvas from this machine"),
+        )
+        .arg(
+            Arg::with_name("show-response")
+                .long("show-response")
+                .required(false)
+                .takes_value(false)
+                .help("Print the server's response body after a successful upload (also shown with --verbose)"),
+        )
+        .arg(
+            Arg::with_name("stdin-name")
+                .long("stdin-name")
+                .required(false)
+                .takes_value(true)
+                .value_name("name")
+                .conflicts_with("watch")
+                .help("Upload what's read from stdin as a single file with this name, instead of walking a directory"),
+        )
+        .arg(
+            Arg::with_name("content-type")
+                .long("content-type")
+                .required(false)
+                .takes_value(true)
+                .requires("stdin-name")
+                .help("The Content-Type for the --stdin-name file [default: guessed from its name]"),
+        )
+        .arg(
+            Arg::with_name("purge-url")
+                .long("purge-url")
+                .required(false)
+                .takes_value(true)
+                .value_name("url")
+                .help("After uploading, POST the uploaded paths as JSON ({\"canvas\": ..., \"paths\": [...]}) here to purge a CDN's cache"),
+        )
+        .arg(
+            Arg::with_name("purge-token")
+                .long("purge-token")
+                .required(false)
+                .takes_value(true)
+                .requires("purge-url")
+                .help("Send this as a bearer token to --purge-url [default: $DARK_PURGE_TOKEN]"),
+        )
+        .arg(
+            Arg::with_name("purge-required")
+                .long("purge-required")
+                .required(false)
+                .takes_value(false)
+                .requires("purge-url")
+                .help("Fail if the purge does, rather than just warning (the upload has still happened)"),
+        )
+        .arg(
+            Arg::with_name("group-by-directory")
+                .long("group-by-directory")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("stdin-name")
+                .help("Send one request per top-level directory, one after another, so a failure only costs that directory's files"),
+        )
+        .arg(
+            Arg::with_name("verify-after-upload")
+                .long("verify-after-upload")
+                .required(false)
+                .takes_value(false)
+                .requires("base-url")
+                .help("After uploading, download each file from --base-url and check it's what was uploaded, failing if any isn't"),
+        )
+        .arg(
+            Arg::with_name("base-url")
+                .long("base-url")
+                .required(false)
+                .takes_value(true)
+                .value_name("url")
+                .help("Where the canvas's static assets are served from, for --verify-after-upload"),
+        )
+        .arg(
+            Arg::with_name("verify-sample")
+                .long("verify-sample")
+                .required(false)
+                .takes_value(true)
+                .value_name("count")
+                .requires("verify-after-upload")
+                .help("With --verify-after-upload, check only this many of the uploaded files, spread through the upload"),
+        )
+        .arg(
+            Arg::with_name("require-clean-git")
+                .long("require-clean-git")
+                .required(false)
+                .takes_value(false)
+                .help("Don't upload if the git repository the dirs are in has uncommitted changes (not checked with --dev)"),
+        )
+        .arg(
+            Arg::with_name("print-config")
+                .long("print-config")
+                .required(false)
+                .takes_value(false)
+                .help("Print the settings this upload would use, from flags, env vars and config files, and exit without uploading"),
+        )
+        .arg(
+            Arg::with_name("yes")
+                .long("yes")
+                .short("y")
+                .required(false)
+                .takes_value(false)
+                .help("Don't ask for confirmation before uploading to https://darklang.com"),
+        )
+        .arg(
+            // troubleshooting only; see the README
+            Arg::with_name("multipart-boundary")
+                .long("multipart-boundary")
+                .required(false)
+                .takes_value(true)
+                .hidden(true)
+                .help("Use this multipart boundary instead of a random one"),
+        )
+        .arg(
+            Arg::with_name("header")
+                .long("header")
+                .short("H")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("'Name: Value'")
+                .help("Send this extra header with the login and upload requests (e.g. for an auth proxy); can be repeated"),
+        )
+        .arg(
+            Arg::with_name("no-jitter")
+                .long("no-jitter")
+                .required(false)
+                .takes_value(false)
+                .help("Retry after exactly 1s, 2s and 4s, rather than randomizing the delays a little"),
+        )
+        .arg(
+            Arg::with_name("size-units")
+                .long("size-units")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&["decimal", "binary"])
+                .default_value("decimal")
+                .help("Print sizes in powers of 1000 (KB, MB) or of 1024 (KiB, MiB)"),
+        )
+        .arg(
+            Arg::with_name("size-breakdown")
+                .long("size-breakdown")
+                .required(false)
+                .takes_value(false)
+                .help("Print the upload's total size by file extension, biggest first (works with --dry-run)"),
+        )
+        .arg(
+            Arg::with_name("list-excluded")
+                .long("list-excluded")
+                .required(false)
+                .takes_value(false)
+                .help("Print each file that was left out of the upload, and why"),
+        )
+        .arg(
+            Arg::with_name("print-request-curl")
+                .long("print-request-curl")
+                .required(false)
+                .takes_value(false)
+                .help("Print a curl command that sends the same upload (with --dry-run, instead of uploading)"),
+        )
+        .arg(
+            Arg::with_name("unsafe-show-secrets")
+                .long("unsafe-show-secrets")
+                .required(false)
+                .takes_value(false)
+                .requires("print-request-curl")
+                .help("Include the session cookie and CSRF token in --print-request-curl's command"),
+        )
+        .arg(
+            Arg::with_name("show-secrets-in-errors")
+                .long("show-secrets-in-errors")
+                .required(false)
+                .takes_value(false)
+                .help("Don't redact the session cookie, CSRF token and --header values in errors and debug output (for local troubleshooting; never in CI)"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("verbose")
+                .help("Don't print progress messages (logging in, where credentials came from)"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .short("v")
+                .required(false)
+                .takes_value(false)
+                .help("Print more about what's happening (e.g. when a login succeeds)"),
         )
-        .header("x-csrf-token", csrf)
-        .header(USER_AGENT, format!("{}/{}", PKG_NAME, VERSION));
+}
 
-    if dryrun {
-        println!("{:#?}", req);
-        println!("{:#?}", form);
-        Ok(())
+fn cli() -> App<'static, 'static> {
+    let app = App::new("dark")
+        .version(VERSION)
+        .author("Ian Smith <ismith@darklang.com")
+        .about("dark cli")
+        .after_help("You can set credentials four ways:\n  --user and --password flags\n  env vars DARK_CLI_USER and DARK_CLI_PASSWORD\n  user and password in a [hosts.\"<host>\"] table in the config files\n  a netrc file at any of $NETRC, ./.netrc, or ~/.netrc\n    (see https://ec.haxx.se/usingcurl/usingcurl-netrc for format)\n\nThe host to upload to is, in order: --host, --dev, $DARK_HOST, the config files, or\nhttps://darklang.com.\n\nSettings are read from /etc/dark/config.toml, then your user config file (e.g.\n~/.config/dark/config.toml), then the nearest .dark file in this directory or above it,\nthen ./dark.toml; each overrides the ones before it, and flags override them all.\n\nUploads to https://darklang.com ask for confirmation first when run from a terminal (pass --yes\nto skip it); uploads to any other host, such as --dev, don't.")
+        .arg(
+            Arg::with_name("canvas")
+                .long("canvas")
+                .required(false)
+                .takes_value(true)
+                .help("Your canvas (or set canvas in dark.toml)"),
+        )
+        .arg(
+            Arg::with_name("canvas-template")
+                .long("canvas-template")
+                .required(false)
+                .takes_value(true)
+                .conflicts_with("canvas")
+                .help("Derive your canvas from git, filling in {branch} and {repo} (e.g. myapp-{branch})"),
+        )
+        .arg(
+            Arg::with_name("dir")
+                .required_unless("stdin-name")
+                .takes_value(true)
+                .help("directory to upload, or a glob such as 'dist/**/*.css' (files are named relative to the part before the first glob)"),
+        );
+    upload_args(app)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("ping")
+                .about("Check that a Darklang host is reachable, without logging in")
+                .after_help("Exits 0 if the host responded, 2 if it couldn't be reached, and 3 if the TLS handshake failed.")
+                .arg(
+                    Arg::with_name("host")
+                        .long("host")
+                        .takes_value(true)
+                        .help("The host to check [default: $DARK_HOST, or https://darklang.com]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("help-patterns")
+                .about("Explain how --exclude patterns and globbed paths match files, with examples")
+                .arg(
+                    Arg::with_name("test-pattern")
+                        .long("test-pattern")
+                        .takes_value(true)
+                        .value_name("glob")
+                        .requires("path")
+                        .help("Instead, say whether this pattern matches <path>"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .requires("test-pattern")
+                        .help("An asset name (a path under the uploaded directory) to test --test-pattern against"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("logout")
+                .about("Clear the cached session for a host, so the next upload logs in again")
+                .arg(
+                    Arg::with_name("host")
+                        .long("host")
+                        .takes_value(true)
+                        .conflicts_with("all")
+                        .help("The host to log out of [default: as for an upload: $DARK_HOST, the config files, or https://darklang.com]"),
+                )
+                .arg(
+                    Arg::with_name("dev")
+                        .long("dev")
+                        .conflicts_with_all(&["host", "all"])
+                        .help("Log out of localhost - debug only."),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .help("Clear the cached sessions for every host"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Check what an upload needs, step by step: the host, TLS, the login endpoint, your credentials and the session cache")
+                .after_help("Nothing is uploaded, and a successful login isn't cached. Each check prints ok, FAIL (with what to try) or skip (when a check it depends on failed). Exits 0 if every check passed, 1 otherwise.")
+                .arg(
+                    Arg::with_name("host")
+                        .long("host")
+                        .takes_value(true)
+                        .help("The host to check [default: as for an upload: $DARK_HOST, the config files, or https://darklang.com]"),
+                )
+                .arg(
+                    Arg::with_name("dev")
+                        .long("dev")
+                        .conflicts_with("host")
+                        .help("Check localhost - debug only."),
+                )
+                .arg(
+                    Arg::with_name("login-url")
+                        .long("login-url")
+                        .takes_value(true)
+                        .value_name("url")
+                        .help("Where to log in [default: as for an upload]"),
+                )
+                .arg(
+                    Arg::with_name("user")
+                        .long("user")
+                        .takes_value(true)
+                        .help("Your dark username [default: as for an upload]"),
+                )
+                .arg(
+                    Arg::with_name("password")
+                        .long("password")
+                        .takes_value(true)
+                        .requires("user")
+                        .help("Your dark password"),
+                )
+                .arg(
+                    Arg::with_name("profile")
+                        .long("profile")
+                        .takes_value(true)
+                        .help("Use the settings from [profile.<name>] in the config files"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check that a deployed asset is byte-for-byte the same as a local file")
+                .after_help("e.g. dark verify --canvas myapp dist/app.js app.js\nThe asset is fetched from where the host serves the canvas (https://myapp.builtwithdark.com for https://darklang.com), unless --base-url says otherwise.\n\nExits 0 if they match, 4 if they don't, 2 if the server couldn't be reached, and 3 if the TLS handshake failed.")
+                .arg(
+                    Arg::with_name("canvas")
+                        .long("canvas")
+                        .takes_value(true)
+                        .help("The canvas the asset was uploaded to [default: as for an upload]"),
+                )
+                .arg(
+                    Arg::with_name("host")
+                        .long("host")
+                        .takes_value(true)
+                        .conflicts_with("base-url")
+                        .help("The host the canvas is on [default: as for an upload: $DARK_HOST, the config files, or https://darklang.com]"),
+                )
+                .arg(
+                    Arg::with_name("dev")
+                        .long("dev")
+                        .conflicts_with_all(&["host", "base-url"])
+                        .help("The canvas is on localhost - debug only."),
+                )
+                .arg(
+                    Arg::with_name("base-url")
+                        .long("base-url")
+                        .takes_value(true)
+                        .conflicts_with("canvas")
+                        .value_name("url")
+                        .help("Fetch the asset from here instead of the canvas's address, e.g. a CDN in front of it"),
+                )
+                .arg(
+                    Arg::with_name("local-path")
+                        .required(true)
+                        .help("The local file to compare against"),
+                )
+                .arg(
+                    Arg::with_name("asset-name")
+                        .required(false)
+                        .help("The deployed asset's name, if it isn't the same as local-path"),
+                ),
+        )
+        .subcommand(upload_args(
+            SubCommand::with_name("manifest")
+                .about("Print the files an upload would send, with their asset names, sizes and hashes, as JSON, without logging in")
+                .after_help("Takes the same flags as a plain upload; the ones that pick and name files (--exclude, --trim-prefix, --name-from-hash, ...) apply.")
+                .arg(
+                    Arg::with_name("summary-only")
+                        .long("summary-only")
+                        .required(false)
+                        .takes_value(false)
+                        .help("Print only how many files there are and their total size, not the files themselves"),
+                )
+                .arg(
+                    Arg::with_name("paths")
+                        .required(true)
+                        .multiple(true)
+                        .help("Directories (or globs) to list; each one's files are named relative to it"),
+                ),
+        ))
+        .subcommand(upload_args(
+            SubCommand::with_name("deploy")
+                .about("Upload directories of static assets to a canvas")
+                .after_help("e.g. dark deploy myapp ./static\nCredentials come from the same places as for a plain upload (see dark --help).")
+                .arg(
+                    Arg::with_name("canvas-name")
+                        .required(true)
+                        .value_name("canvas")
+                        .help("The canvas to upload to"),
+                )
+                .arg(
+                    Arg::with_name("paths")
+                        .required_unless("stdin-name")
+                        .multiple(true)
+                        .help("Directories (or globs, such as 'dist/**/*.css') to upload; each one's files are named relative to it"),
+                ),
+        ))
+}
+
+#[derive(Serialize)]
+struct ManifestFile<'a> {
+    path: String,
+    name: &'a str,
+    hash: String,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    // what every file's `hash` is (--hash-algorithm)
+    hash_algorithm: &'static str,
+    files: Vec<ManifestFile<'a>>,
+}
+
+// What `dark manifest --summary-only` prints instead.
+#[derive(Serialize)]
+struct ManifestSummary {
+    files: usize,
+    // in bytes
+    total_size: u64,
+}
+
+// `dark manifest`: walk the dirs with all the upload's filters and renames, and print what would
+// be uploaded, hashes included, as JSON (or YAML) sorted by asset name. It doesn't log in or upload.
+// With --summary-only, just the number of files and their total size.
+fn print_manifest(opts: &UploadOptions, summary_only: bool) -> Result<(), DarkError> {
+    let FormBody {
+        mut entries,
+        mut warnings,
+        hashes,
+        size,
+        ..
+    } = form_body(opts)?;
+    let checked = check_lockfile(opts, &hashes, &mut warnings);
+    if checked.is_err() {
+        print_warnings(&warnings);
+    }
+    checked?;
+    if summary_only {
+        let summary = ManifestSummary {
+            files: entries.len(),
+            total_size: size,
+        };
+        if opts.yaml {
+            println!("{}", serde_yaml::to_string(&summary).unwrap_or_default());
+        } else {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&summary).unwrap_or_default()
+            );
+        }
+        print_warnings(&warnings);
+        return check_warnings(opts, &warnings);
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut files = vec![];
+    for entry in &entries {
+        files.push(ManifestFile {
+            path: entry.path.display().to_string(),
+            name: &entry.name,
+            // opts.hash_files is set for the manifest, so every entry has its digest
+            hash: entry.hash.clone().unwrap_or_default(),
+            size: entry.size,
+        });
+    }
+    let manifest = Manifest {
+        hash_algorithm: opts.hash_algorithm.name(),
+        files,
+    };
+    if opts.yaml {
+        println!("{}", serde_yaml::to_string(&manifest).unwrap_or_default());
     } else {
-        req.multipart(form)
-            .send()
-            .or_else(|error| {
-                println!("Err: {:?}", error);
-                Err(DarkError::Upload(error))
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&manifest).unwrap_or_default()
+        );
+    }
+    print_warnings(&warnings);
+    check_warnings(opts, &warnings)
+}
+
+// The settings an upload would use, once the flags, env vars and config files are all resolved,
+// as printed by --print-config.
+#[derive(Serialize)]
+struct EffectiveConfig<'a> {
+    config_files: Vec<String>,
+    profile: Option<&'a str>,
+    host: &'a str,
+    login_url: &'a str,
+    canvas: &'a str,
+    dirs: &'a [&'a str],
+    cache: bool,
+    csrf_header: &'a str,
+    compress_level: Option<u32>,
+    dry_run: bool,
+    credentials: Option<EffectiveCredentials<'a>>,
+}
+
+#[derive(Serialize)]
+struct EffectiveCredentials<'a> {
+    source: &'a str,
+    user: &'a str,
+    password: &'static str,
+}
+
+// For --print-config: print the resolved settings (as TOML, or JSON or YAML under --output-format)
+// without logging in or uploading. The password is never printed.
+fn print_config(
+    opts: &UploadOptions,
+    profile: Option<&str>,
+    config_files: &[PathBuf],
+    creds: Option<&(String, String, String)>,
+) -> Result<(), DarkError> {
+    let config = EffectiveConfig {
+        config_files: config_files
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect(),
+        profile,
+        host: opts.host,
+        login_url: &opts.login_url,
+        canvas: &opts.canvas,
+        dirs: &opts.dirs,
+        cache: opts.use_cache,
+        csrf_header: opts.csrf_header,
+        compress_level: opts.compress,
+        dry_run: opts.dryrun,
+        credentials: creds.map(|&(ref user, _, ref source)| EffectiveCredentials {
+            source,
+            user,
+            password: secrets::REDACTED,
+        }),
+    };
+    if opts.yaml {
+        println!("{}", serde_yaml::to_string(&config).unwrap_or_default());
+    } else if opts.output_format == OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&config).unwrap_or_default()
+        );
+    } else {
+        let toml = toml::to_string(&config).map_err(|err| {
+            DarkError::InvalidArgument("print-config".to_string(), err.to_string())
+        })?;
+        print!("{}", toml);
+    }
+    Ok(())
+}
+
+// With --require-clean-git, refuse to upload from a git repository with uncommitted changes,
+// listing them. A --dev upload is only ever local, so it's let through.
+fn check_clean_git(matches: &ArgMatches, opts: &UploadOptions) -> Result<(), DarkError> {
+    if !matches.is_present("require-clean-git") {
+        return Ok(());
+    }
+    if matches.is_present("dev") {
+        if opts.verbosity != Verbosity::Quiet && opts.output_format == OutputFormat::Text {
+            println!(
+                "Not checking for uncommitted changes (--require-clean-git), since this is --dev."
+            );
+        }
+        return Ok(());
+    }
+    let dirty = git::uncommitted_changes(&opts.dirs)?;
+    if dirty.is_empty() {
+        return Ok(());
+    }
+    Err(DarkError::UncommittedChanges(
+        dirty
+            .iter()
+            .map(|&(ref repo, ref changes)| format!("In {}:\n\t{}", repo, changes.join("\n\t")))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    ))
+}
+
+// Whether --output-format yaml was asked for, which is only for the documents dark prints: the
+// manifest and --print-config.
+fn check_yaml(matches: &ArgMatches, manifest_only: bool) -> Result<bool, DarkError> {
+    let yaml = matches.value_of("output-format") == Some("yaml") && !matches.is_present("json");
+    if yaml && !manifest_only && !matches.is_present("print-config") {
+        return Err(DarkError::InvalidArgument(
+            "output-format".to_string(),
+            "yaml is only for dark manifest and --print-config; use text, json or tsv for an upload"
+                .to_string(),
+        ));
+    }
+    Ok(yaml)
+}
+
+// Whether --output-format json (or --json) was asked for, at the top level or on deploy; the
+// manifest command only speaks JSON.
+fn wants_json(matches: &ArgMatches) -> bool {
+    if matches.subcommand_matches("manifest").is_some() {
+        return true;
+    }
+    let matches = matches.subcommand_matches("deploy").unwrap_or(matches);
+    matches.is_present("json") || matches.value_of("output-format") == Some("json")
+}
+
+// The username and password to log in to `host` with, and where they came from: in order,
+// --user and --password, $DARK_CLI_USER and $DARK_CLI_PASSWORD, the config files' [hosts."<url>"]
+// table for the host, then netrc.
+fn find_credentials(
+    user: Option<&str>,
+    password: Option<&str>,
+    settings: &config::Settings,
+    host: &str,
+) -> Result<Option<(String, String, String)>, DarkError> {
+    // first we check for username/password in command line flags
+    let creds = match (user, password) {
+        (Some(user), Some(password)) => {
+            Some((user.to_string(), password.to_string(), "flags".to_string()))
+        }
+        (_, _) => None,
+    }
+    .or_else(|| {
+        // then we check for env vars $DARK_CLI_USER and $DARK_CLI_PASSWORD
+        match (env::var("DARK_CLI_USER"), env::var("DARK_CLI_PASSWORD")) {
+            (Ok(username), Ok(password)) => Some((username, password, "env vars".to_string())),
+            _ => None,
+        }
+    })
+    .map(Ok)
+    .or_else(|| {
+        // then the [hosts."<url>"] table for this host in the config files
+        settings
+            .credentials(host)
+            .map(|creds| {
+                creds.map(|(user, password)| {
+                    let source = format!("[hosts.\"{}\"] in the config files", host);
+                    (user, password, source)
+                })
+            })
+            .transpose()
+    })
+    .transpose()?
+    .or_else(|| {
+        // then we try netrc, via (in order):
+        // - the file at $NETRC
+        // - the file at ./.netrc
+        // - the file at ~/.netrc
+        let netrc_home = dirs::home_dir()
+            .and_then(|mut netrc_home| {
+                netrc_home.push(".netrc");
+                Some(netrc_home)
+            })
+            .unwrap_or_default();
+
+        let netrc_env = env::var("NETRC").unwrap_or_default();
+
+        let netrc_path: &str = if Path::new(&netrc_env).is_file() {
+            netrc_env.as_str()
+        } else if Path::new("./.netrc").is_file() {
+            "./.netrc"
+        } else if Path::new(&netrc_home).is_file() {
+            netrc_home.to_str().unwrap_or_default()
+        } else {
+            ""
+        };
+        let netrc = File::open(netrc_path)
+            .ok()
+            .map(BufReader::new)
+            .and_then(|bufr| Netrc::parse(bufr).ok());
+
+        let netrc_machine: Option<String> = host
+            .parse::<Uri>()
+            .and_then(|uri| {
+                Ok(match uri.host() {
+                    Some(h) => h.to_owned(),
+                    _ => "".to_owned(),
+                })
+            })
+            .ok();
+
+        let netrc_creds: Option<(String, String)> = match (netrc, netrc_machine) {
+            (Some(netrc), Some(netrc_machine)) => netrc
+                .hosts
+                .iter()
+                .find(|(k, _nm)| *k == netrc_machine)
+                .map(|(_, nm)| {
+                    (
+                        nm.login.clone(),
+                        nm.password.as_ref().unwrap_or(&"".to_string()).clone(),
+                    )
+                }),
+            (_, _) => None,
+        };
+
+        netrc_creds.map(|(user, password)| (user, password, format!("netrc at {}", netrc_path)))
+    });
+    Ok(creds)
+}
+
+fn app(matches: &ArgMatches) -> Result<(), DarkError> {
+    if let Some(ping_matches) = matches.subcommand_matches("ping") {
+        let host = ping_matches
+            .value_of("host")
+            .map(str::to_string)
+            .or_else(env_host)
+            .unwrap_or_else(|| DEFAULT_HOST.to_string());
+        return ping(&host);
+    }
+
+    if let Some(logout_matches) = matches.subcommand_matches("logout") {
+        return logout(logout_matches);
+    }
+
+    if let Some(doctor_matches) = matches.subcommand_matches("doctor") {
+        return doctor_command(doctor_matches);
+    }
+
+    if let Some(patterns_matches) = matches.subcommand_matches("help-patterns") {
+        return help_patterns(patterns_matches);
+    }
+
+    if let Some(verify_matches) = matches.subcommand_matches("verify") {
+        return verify_command(verify_matches);
+    }
+
+    // `dark deploy <canvas> <paths>...` takes positionally what the top-level form takes as
+    // --canvas and dir; every other flag means the same in both.
+    // `dark manifest <paths>...` takes the same flags again, but only walks.
+    let manifest_only = matches.subcommand_matches("manifest").is_some();
+    let (matches, canvas, dirs, dirs_arg): (&ArgMatches, Option<&str>, Vec<&str>, &str) =
+        match matches
+            .subcommand_matches("deploy")
+            .or_else(|| matches.subcommand_matches("manifest"))
+        {
+            Some(sub_matches) => (
+                sub_matches,
+                sub_matches.value_of("canvas-name"),
+                sub_matches
+                    .values_of("paths")
+                    .map(Iterator::collect)
+                    .unwrap_or_else(Vec::new),
+                "paths",
+            ),
+            None => (
+                matches,
+                matches.value_of("canvas"),
+                matches.value_of("dir").into_iter().collect(),
+                "dir",
+            ),
+        };
+    // An empty path (a stray quoted space, or an unset variable in `dark deploy c "$DIST"`) isn't
+    // a directory anyone meant to upload, so leave it out; if that leaves none, say so.
+    let dirs: Vec<&str> = dirs
+        .into_iter()
+        .filter(|dir| !dir.trim().is_empty())
+        .collect();
+    if dirs.is_empty() && !matches.is_present("stdin-name") {
+        return Err(DarkError::MissingArgument(dirs_arg.to_string()));
+    }
+    let ignore_case = matches.is_present("ignore-case")
+        || (filter::IGNORE_CASE_BY_DEFAULT && !matches.is_present("case-sensitive-globs"));
+    let paths = filter::Paths::new(&dirs, matches.value_of("only"), ignore_case)?;
+    let dirs = paths.dirs.clone();
+    // the config files fill in whatever the flags don't set
+    let settings = config::load(
+        matches.value_of("profile"),
+        !matches.is_present("no-system-config"),
+    )?;
+    let canvas = match matches.value_of("canvas-template") {
+        Some(template) => {
+            let canvas = git::canvas_from_template(template)?;
+            eprintln!("Using canvas {} (from --canvas-template).", canvas);
+            canvas
+        }
+        None if manifest_only => String::new(),
+        None => canvas
+            .map(str::to_string)
+            .or_else(|| settings.canvas.clone())
+            .ok_or_else(|| DarkError::MissingArgument("canvas".to_string()))?,
+    };
+    let user = matches.value_of("user");
+    let password = matches.value_of("password");
+    // --host, then --dev, then $DARK_HOST, then the config files
+    let host = match matches.value_of("host") {
+        Some(host) => host.trim_end_matches('/').to_string(),
+        None if matches.is_present("dev") => "http://darklang.localhost:8000".to_string(),
+        None => env_host()
+            .or_else(|| settings.host.clone())
+            .unwrap_or_else(|| DEFAULT_HOST.to_string()),
+    };
+    let host = host.as_str();
+    let watch_dir = matches.is_present("watch");
+    let report_path = matches.value_of("report");
+    // before anything that might hold a secret is made
+    secrets::set_shown(matches.is_present("show-secrets-in-errors"));
+    let hash_algorithm = matches
+        .value_of("hash-algorithm")
+        .and_then(HashAlgorithm::from_name)
+        .unwrap_or(HashAlgorithm::Sha256);
+    let last_deploy = if matches.is_present("since-last-deploy") {
+        Some(state::load(host, &canvas, hash_algorithm).unwrap_or_else(|| {
+            if !matches.is_present("quiet") && !wants_json(matches) {
+                println!(
+                    "No record of a previous deploy to {} on {} with {} hashes; uploading everything.",
+                    canvas,
+                    host,
+                    hash_algorithm.name()
+                );
+            }
+            BTreeMap::new()
+        }))
+    } else {
+        None
+    };
+    let hash_files = matches.is_present("checksums")
+        || matches.is_present("name-from-hash")
+        || last_deploy.is_some()
+        || manifest_only
+        || matches.is_present("lockfile")
+        || matches.is_present("frozen");
+    let opts = UploadOptions {
+        hash_files,
+        hash_as_sent: !hash_files
+            && (report_path.is_some() || matches.is_present("verify-after-upload")),
+        hash_algorithm,
+        last_deploy,
+        group_by_directory: matches.is_present("group-by-directory"),
+        verify_base_url: if matches.is_present("verify-after-upload") {
+            matches.value_of("base-url")
+        } else {
+            None
+        },
+        verify_sample: matches
+            .value_of("verify-sample")
+            .map(|sample| match sample.parse::<usize>() {
+                Ok(sample) if sample > 0 => Ok(sample),
+                _ => Err(DarkError::InvalidArgument(
+                    "verify-sample".to_string(),
+                    "expected a number of files, at least 1".to_string(),
+                )),
             })
-            .and_then(|mut response| match response.status() {
-                StatusCode::OK => {
-                    println!("Upload succeeded!");
-                    Ok(())
+            .transpose()?,
+        login_url: login_url(matches.value_of("login-url"), &settings, host),
+        host,
+        canvas,
+        dirs,
+        dryrun: matches.is_present("dry-run"),
+        strict: matches.is_present("strict"),
+        field_name: matches.value_of("field-name"),
+        checksums: matches.is_present("checksums"),
+        cache_control: cache_control::CacheControl::new(
+            &matches
+                .values_of("cache-control")
+                .map(Iterator::collect)
+                .unwrap_or_else(Vec::new),
+            matches.is_present("cache-preset"),
+            ignore_case,
+        )?,
+        hash_names: if matches.is_present("name-from-hash") {
+            Some(
+                matches
+                    .value_of("hash-length")
+                    .and_then(|length| length.parse::<usize>().ok())
+                    .filter(|length| *length > 0 && *length <= 64)
+                    .ok_or_else(|| {
+                        DarkError::InvalidArgument(
+                            "hash-length".to_string(),
+                            "expected a number from 1 to 64".to_string(),
+                        )
+                    })?,
+            )
+        } else {
+            None
+        },
+        manifest: matches.value_of("manifest"),
+        trim_prefix: matches.value_of("trim-prefix"),
+        release: matches.value_of("release").map(check_release).transpose()?,
+        list_files: if matches.is_present("list-files") {
+            Some(true)
+        } else if matches.is_present("no-list-files") {
+            Some(false)
+        } else {
+            None
+        },
+        csrf_header: matches.value_of("csrf-header").unwrap_or(CSRF_HEADER),
+        compress: if matches.is_present("compress") {
+            Some(
+                matches
+                    .value_of("compress-level")
+                    .and_then(|level| level.parse::<u32>().ok())
+                    .filter(|level| *level <= 9)
+                    .ok_or_else(|| {
+                        DarkError::InvalidArgument(
+                            "compress-level".to_string(),
+                            "expected a number from 0 to 9".to_string(),
+                        )
+                    })?,
+            )
+        } else {
+            None
+        },
+        output_format: match matches.value_of("output-format") {
+            // the manifest is JSON, so keep the walk's text chatter out of it
+            _ if manifest_only || matches.is_present("json") => OutputFormat::Json,
+            // yaml is only for the manifest and --print-config, which print a document in place of
+            // the listing, so like json it keeps the chatter out
+            Some("json") | Some("yaml") => OutputFormat::Json,
+            Some("tsv") => OutputFormat::Tsv,
+            _ => OutputFormat::Text,
+        },
+        // an operator can turn the cache off for a whole machine (say, a shared CI runner) with
+        // $DARK_NO_CACHE or cache = false in a config file, without touching every invocation
+        use_cache: !matches.is_present("no-cache")
+            && !env_no_cache()
+            && settings.cache != Some(false),
+        paths,
+        allow_empty: matches.is_present("allow-empty"),
+        filters: filter::Filters::new(
+            &matches
+                .values_of("exclude")
+                .map(Iterator::collect)
+                .unwrap_or_else(Vec::new),
+            &matches
+                .values_of("exclude-from")
+                .map(Iterator::collect)
+                .unwrap_or_else(Vec::new),
+            matches
+                .value_of("min-file-size")
+                .and_then(|size| size.parse::<u64>().ok())
+                .ok_or_else(|| {
+                    DarkError::InvalidArgument(
+                        "min-file-size".to_string(),
+                        "expected a number of bytes".to_string(),
+                    )
+                })?,
+            matches.is_present("skip-empty"),
+            ignore_case,
+        )?,
+        list_excluded: matches.is_present("list-excluded"),
+        print_curl: matches.is_present("print-request-curl"),
+        show_secrets: matches.is_present("unsafe-show-secrets"),
+        normalize_text: matches.is_present("normalize-text"),
+        sanitize_names: matches.is_present("sanitize-names"),
+        warnings_as_errors: matches.is_present("warnings-as-errors"),
+        size_breakdown: matches.is_present("size-breakdown"),
+        show_response: matches.is_present("show-response"),
+        stdin_name: matches.value_of("stdin-name"),
+        content_type: matches.value_of("content-type"),
+        purge_url: matches.value_of("purge-url"),
+        purge_token: matches
+            .value_of("purge-token")
+            .map(str::to_string)
+            .or_else(|| env::var("DARK_PURGE_TOKEN").ok()),
+        purge_required: matches.is_present("purge-required"),
+        assume_yes: matches.is_present("yes"),
+        fail_fast_auth: !matches.is_present("no-fail-fast-auth"),
+        allow_external_links: matches.is_present("allow-external-links"),
+        timings: matches.is_present("timings"),
+        yaml: check_yaml(matches, manifest_only)?,
+        name_map: match matches.value_of("map-names-from") {
+            Some(path) => read_name_map(path)?,
+            None => BTreeMap::new(),
+        },
+        auth_command: matches.value_of("auth-command"),
+        lockfile: if matches.is_present("lockfile") || matches.is_present("frozen") {
+            Some(lockfile::PATH)
+        } else {
+            None
+        },
+        frozen: matches.is_present("frozen"),
+        headers: parse_headers(
+            &matches
+                .values_of("header")
+                .map(Iterator::collect)
+                .unwrap_or_else(Vec::new),
+            matches.value_of("csrf-header").unwrap_or(CSRF_HEADER),
+        )?,
+        multipart_boundary: match matches.value_of("multipart-boundary") {
+            Some(boundary) => {
+                multipart::check_boundary(boundary).map_err(|reason| {
+                    DarkError::InvalidArgument("multipart-boundary".to_string(), reason)
+                })?;
+                Some(boundary)
+            }
+            None => None,
+        },
+        size_units: match matches.value_of("size-units") {
+            Some("binary") => SizeUnits::Binary,
+            _ => SizeUnits::Decimal,
+        },
+        verbosity: if matches.is_present("quiet") {
+            Verbosity::Quiet
+        } else if matches.is_present("verbose") {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        },
+    };
+
+    retry::set_jitter(!matches.is_present("no-jitter"));
+    if manifest_only {
+        return print_manifest(&opts, matches.is_present("summary-only"));
+    }
+    check_clean_git(matches, &opts)?;
+
+    let creds = find_credentials(user, password, &settings, host)?;
+
+    if matches.is_present("print-config") {
+        return print_config(
+            &opts,
+            matches.value_of("profile"),
+            &config::files(!matches.is_present("no-system-config")),
+            creds.as_ref(),
+        );
+    }
+
+    let (user, password, cred_source) = match (creds, opts.auth_command) {
+        // the helper stands in for a username and password
+        (_, Some(command)) => (
+            String::new(),
+            String::new(),
+            format!("--auth-command {}", command),
+        ),
+        (Some(c), None) => c,
+        (None, None) => return Err(DarkError::NoCredentials(host.to_string())),
+    };
+    if opts.verbosity != Verbosity::Quiet {
+        eprintln!("Using credentials from {}.", cred_source);
+    }
+
+    let started = Instant::now();
+    let mut log = UploadLog::default();
+    let result = login_and_upload(&opts, user, password, &mut log);
+    // recording the deploy, the purge and --verify-after-upload (their warnings join the rest)
+    let after = after_outcome(
+        &opts,
+        &log.entries,
+        &log.hashes,
+        result.as_ref().map(|_| ()),
+        false,
+    );
+    log.warnings.extend(warning::take());
+    print_warnings(&log.warnings);
+    if opts.timings {
+        print_timings(&opts, &log.timings);
+    }
+    print_results(&opts, &log, result.as_ref().map(|_| ()));
+
+    if let Some(report_path) = report_path {
+        let outcome = result.as_ref().map(|_| ());
+        report::Report::new(&opts, &cred_source, &log, outcome, started.elapsed())
+            .write(report_path)?;
+        eprintln!("Wrote report to {}.", report_path);
+    }
+
+    let session = result?;
+    after?;
+    check_late_warnings(&opts, &log.warnings)?;
+
+    if watch_dir {
+        // Re-upload just what changed, reusing the session we already have rather than
+        // re-authing every cycle.
+        watch(&opts.dirs, |files| {
+            // --watch sees every change, so apply the same filters the walk did
+            let mut included = vec![];
+            let mut excluded = vec![];
+            for (dir, file) in files {
+                let name = asset_name(dir, &file)?;
+                if opts.paths.matches(dir, &name).is_none() {
+                    continue;
                 }
-                _ => {
-                    let exec_id = response
-                        .headers()
-                        .get("X-Darklang-Execution-ID")
-                        .and_then(|header| header.to_str().ok())
-                        .unwrap_or("<Unknown>")
-                        .to_string();
-                    Err(DarkError::Non200Response(
-                        response
-                            .text()
-                            .unwrap_or_else(|_| String::from("<Undecodable>")),
-                        response.status().as_u16(),
-                        exec_id,
-                    ))
+                let size = std::fs::metadata(&file)
+                    .map(|metadata| metadata.len())
+                    .unwrap_or(0);
+                match opts.filters.exclusion(&name, size) {
+                    Some(exclusion) => excluded.push(exclusion),
+                    None => included.push((dir, file)),
                 }
-            })
+            }
+            if opts.list_excluded && !excluded.is_empty() {
+                print_excluded(&opts, &excluded);
+            }
+            if included.is_empty() {
+                return Ok(());
+            }
+            let FormBody {
+                forms,
+                mut entries,
+                size,
+                skipped,
+                mut warnings,
+                hashes,
+                ..
+            } = form_from_files(&opts, included)?;
+            warnings.extend(warning::take());
+            if let Err(err) = check_warnings(&opts, &warnings) {
+                print_warnings(&warnings);
+                return Err(err);
+            }
+            if opts.output_format == OutputFormat::Text {
+                print_file_list(&opts, &entries);
+            } else {
+                print_files(&opts, &entries, size, &skipped)?;
+            }
+            if let Some(manifest) = opts.manifest {
+                write_name_manifest(manifest, &entries, true)?;
+            }
+            if entries.is_empty() {
+                return Ok(());
+            }
+            let uploaded = upload_forms(&opts, &session, &forms, &mut BTreeSet::new());
+            fill_sent_hashes(&opts, &forms, &mut entries);
+            let after = after_outcome(
+                &opts,
+                &entries,
+                &hashes,
+                uploaded.as_ref().map(|_| ()),
+                true,
+            );
+            warnings.extend(warning::take());
+            print_warnings(&warnings);
+            uploaded?;
+            after?;
+            check_late_warnings(&opts, &warnings)?;
+            if opts.output_format == OutputFormat::Text {
+                println!(
+                    "[{}] Uploaded {} changed file(s) totalling {}.",
+                    timestamp(),
+                    entries.len(),
+                    opts.size_units.format(size)?
+                );
+            }
+            Ok(())
+        })?;
     }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ErrorDetails {
+    kind: &'static str,
+    message: String,
+    exit_code: i32,
+}
+
+// A failure as printed under --output-format json, on stdout like the file listing.
+#[derive(Serialize)]
+struct ErrorOutput {
+    error: ErrorDetails,
 }
 
 fn main() {
-    match app() {
+    let matches = cli().get_matches();
+    match app(&matches) {
         Ok(()) => (),
         Err(err) => {
-            eprintln!("{}", err);
-            std::process::exit(1);
+            if wants_json(&matches) {
+                let output = ErrorOutput {
+                    error: ErrorDetails {
+                        kind: err.kind(),
+                        message: err.to_string(),
+                        exit_code: err.exit_code(),
+                    },
+                };
+                println!("{}", serde_json::to_string(&output).unwrap_or_default());
+            } else {
+                eprintln!("{}", err);
+            }
+            std::process::exit(err.exit_code());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The options for a plain upload of `dirs`, as if no flags were given.
+    fn options<'a>(dirs: &[&'a str]) -> UploadOptions<'a> {
+        UploadOptions {
+            host: DEFAULT_HOST,
+            login_url: LOGIN_URL.to_string(),
+            canvas: "test-canvas".to_string(),
+            dirs: dirs.to_vec(),
+            dryrun: false,
+            strict: false,
+            field_name: None,
+            checksums: false,
+            cache_control: cache_control::CacheControl::new(&[], false, false).unwrap(),
+            hash_names: None,
+            manifest: None,
+            trim_prefix: None,
+            release: None,
+            list_files: None,
+            csrf_header: CSRF_HEADER,
+            compress: None,
+            output_format: OutputFormat::Json,
+            use_cache: false,
+            paths: filter::Paths::new(dirs, None, false).unwrap(),
+            allow_empty: false,
+            filters: filter::Filters::new(&[], &[], 0, false, false).unwrap(),
+            list_excluded: false,
+            verbosity: Verbosity::Quiet,
+            print_curl: false,
+            show_secrets: false,
+            normalize_text: false,
+            sanitize_names: false,
+            warnings_as_errors: false,
+            size_breakdown: false,
+            show_response: false,
+            stdin_name: None,
+            content_type: None,
+            purge_url: None,
+            purge_token: None,
+            purge_required: false,
+            size_units: SizeUnits::Decimal,
+            assume_yes: true,
+            multipart_boundary: None,
+            headers: HeaderMap::new(),
+            lockfile: None,
+            frozen: false,
+            auth_command: None,
+            timings: false,
+            yaml: false,
+            name_map: BTreeMap::new(),
+            allow_external_links: false,
+            fail_fast_auth: true,
+            hash_files: false,
+            hash_as_sent: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+            last_deploy: None,
+            verify_base_url: None,
+            verify_sample: None,
+            group_by_directory: false,
+        }
+    }
+
+    #[test]
+    fn canvas_base_urls() {
+        assert_eq!(
+            canvas_base_url("https://darklang.com", "myapp")
+                .as_ref()
+                .map(String::as_str),
+            Some("https://myapp.builtwithdark.com")
+        );
+        assert_eq!(
+            canvas_base_url("http://darklang.localhost:8000", "myapp")
+                .as_ref()
+                .map(String::as_str),
+            Some("http://myapp.builtwithdark.localhost:8000")
+        );
+        assert_eq!(canvas_base_url("https://dark.example.com", "myapp"), None);
+    }
+
+    #[test]
+    fn login_redirects_are_only_followed_within_the_same_origin() {
+        let follows = |location| {
+            follows_redirect(
+                "https://darklang.com/dark-cli",
+                &reqwest::Url::parse(location).unwrap(),
+            )
+        };
+        assert!(follows("https://darklang.com/api/dark-cli"));
+        assert!(follows("https://darklang.com:443/dark-cli/"));
+        assert!(!follows("http://darklang.com/dark-cli"));
+        assert!(!follows("https://darklang.com:8443/dark-cli"));
+        assert!(!follows("https://sso.example.com/login"));
+    }
+
+    #[test]
+    fn html_asking_to_log_in_is_a_login_page() {
+        assert!(looks_like_login_page(
+            "<!DOCTYPE html><html><form action=\"/login\"></form></html>"
+        ));
+        assert!(looks_like_login_page("\n  <div>Please Sign In</div>"));
+        assert!(!looks_like_login_page(
+            "<html><body>Not found</body></html>"
+        ));
+        // a JSON error that mentions a password isn't a page
+        assert!(!looks_like_login_page("{\"error\": \"wrong password\"}"));
+    }
+
+    #[test]
+    fn auth_challenges_are_described_by_scheme_and_realm() {
+        assert_eq!(
+            describe_challenge("Basic realm=\"dark\"").unwrap(),
+            "Basic auth, realm \"dark\""
+        );
+        assert_eq!(
+            describe_challenge(" Bearer error=\"invalid_token\", Realm=\"api\"").unwrap(),
+            "Bearer auth, realm \"api\""
+        );
+        assert_eq!(describe_challenge("Negotiate").unwrap(), "Negotiate auth");
+        assert_eq!(describe_challenge("  "), None);
+    }
+
+    #[test]
+    fn only_darks_exact_answer_counts_as_a_csrf_rejection() {
+        assert!(is_csrf_rejection(StatusCode::UNAUTHORIZED, "Bad CSRF"));
+        assert!(is_csrf_rejection(StatusCode::FORBIDDEN, "Bad CSRF\n"));
+        assert!(!is_csrf_rejection(
+            StatusCode::FORBIDDEN,
+            "Forbidden: csrf tokens are not accepted from this network"
+        ));
+        assert!(!is_csrf_rejection(StatusCode::UNAUTHORIZED, "Unauthorized"));
+        assert!(!is_csrf_rejection(StatusCode::BAD_REQUEST, "Bad CSRF"));
+    }
+
+    #[test]
+    fn hashed_names_put_the_hash_before_the_extension() {
+        let hash = "1a2b3c4d5e6f";
+        assert_eq!(hashed_name("js/app.js", hash, 8), "js/app.1a2b3c4d.js");
+        assert_eq!(hashed_name("app.min.css", hash, 4), "app.min.1a2b.css");
+        assert_eq!(hashed_name("LICENSE", hash, 8), "LICENSE.1a2b3c4d");
+        assert_eq!(
+            hashed_name("v1.2/.htaccess", hash, 8),
+            "v1.2/.htaccess.1a2b3c4d"
+        );
+        assert_eq!(hashed_name("app.js", hash, 64), "app.1a2b3c4d5e6f.js");
+    }
+
+    #[test]
+    fn trim_prefix_only_removes_whole_leading_directories() {
+        assert_eq!(
+            trim_prefix("static/js/app.js", "static"),
+            Some("js/app.js".to_string())
+        );
+        assert_eq!(
+            trim_prefix("static/js/app.js", "/static/js/"),
+            Some("app.js".to_string())
+        );
+        assert_eq!(trim_prefix("app.js", "/"), Some("app.js".to_string()));
+        assert_eq!(trim_prefix("statically/app.js", "static"), None);
+        assert_eq!(trim_prefix("static", "static"), None);
+        assert_eq!(trim_prefix("js/static/app.js", "static"), None);
+    }
+
+    #[test]
+    fn sanitized_names_have_no_reserved_characters_or_empty_segments() {
+        assert_eq!(sanitize_name("my file.css"), "my-file.css");
+        assert_eq!(sanitize_name("//js///app.js"), "js/app.js");
+        assert_eq!(sanitize_name("app.js?v=2#top"), "app.jsv=2top");
+        assert_eq!(sanitize_name("a\tb\u{7f}\n.txt"), "ab.txt");
+        assert_eq!(sanitize_name("/?/#/x"), "x");
+        assert_eq!(sanitize_name("img/logo.png"), "img/logo.png");
+    }
+
+    #[test]
+    fn extra_headers_are_parsed_and_may_not_replace_ours() {
+        let headers =
+            parse_headers(&["X-Team: web", "x-team:ops ", "X-Empty:"], "X-CSRF-Token").unwrap();
+        let team: Vec<_> = headers.get_all("x-team").iter().collect();
+        assert_eq!(team, vec!["web", "ops"]);
+        assert_eq!(headers.get("x-empty").unwrap(), "");
+
+        let rejected = |value| match parse_headers(&[value], "X-CSRF-Token") {
+            Err(DarkError::InvalidArgument(flag, reason)) => {
+                assert_eq!(flag, "header");
+                reason
+            }
+            _ => panic!("{:?} was accepted", value),
+        };
+        assert!(rejected("X-Team web").contains("isn't of the form"));
+        assert!(rejected("Bad Name: x").contains("isn't a valid header name"));
+        assert_eq!(rejected("Cookie: a=b"), "cookie is set by dark-cli itself");
+        assert_eq!(
+            rejected("x-csrf-token: t"),
+            "x-csrf-token is set by dark-cli itself"
+        );
+        assert!(rejected("X-Team: a\nb").contains("has an invalid value"));
+    }
+
+    #[test]
+    fn asset_urls_percent_encode_each_segment() {
+        let url = |base, name| asset_url(base, name).unwrap().to_string();
+        assert_eq!(
+            url("https://myapp.builtwithdark.com", "js/app.js"),
+            "https://myapp.builtwithdark.com/js/app.js"
+        );
+        assert_eq!(
+            url("https://cdn.example.com/static/", "/my file#1?.css"),
+            "https://cdn.example.com/static/my%20file%231%3F.css"
+        );
+        assert!(asset_url("not a url", "app.js").is_err());
+    }
+
+    #[test]
+    fn yaml_manifests_keep_names_that_look_like_other_scalars_as_strings() {
+        let names = ["0x1F", "1_000", "2020-05-01", "true", "1e3", "null", "- a"];
+        let manifest = Manifest {
+            hash_algorithm: "sha256",
+            files: names
+                .iter()
+                .map(|&name| ManifestFile {
+                    path: name.to_string(),
+                    name,
+                    hash: String::new(),
+                    size: 0,
+                })
+                .collect(),
+        };
+        let yaml = serde_yaml::to_string(&manifest).unwrap();
+        assert!(yaml.contains("name: \"0x1F\""), "{}", yaml);
+        let parsed: serde_json::Value = serde_yaml::from_str(&yaml).unwrap();
+        for (file, name) in parsed["files"].as_array().unwrap().iter().zip(&names) {
+            assert_eq!(file["name"], serde_json::Value::from(*name));
+        }
+    }
+
+    fn form_text(form: &multipart::Form) -> String {
+        let mut body = String::new();
+        form.reader().read_to_string(&mut body).unwrap();
+        body
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_upload_under_the_link_name_with_the_target_bytes() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("site");
+        std::fs::create_dir_all(root.join("build-123")).unwrap();
+        std::fs::create_dir_all(root.join("links")).unwrap();
+        std::fs::write(root.join("build-123/app.js"), "console.log(123);").unwrap();
+        symlink("../build-123/app.js", root.join("links/latest")).unwrap();
+        symlink("../build-123/gone.js", root.join("links/dangling")).unwrap();
+
+        // the file, and the link to it at the file's size
+        let dir = root.to_str().unwrap();
+        let body = form_body(&options(&[dir])).unwrap();
+        assert_eq!(body.size, 2 * "console.log(123);".len() as u64);
+        assert!(body.skipped.is_empty());
+        let mut names: Vec<&str> = body.entries.iter().map(|e| e.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["build-123/app.js", "links/latest"]);
+        let text = form_text(&body.forms[0].form);
+        let link_part = text
+            .split("\r\n--")
+            .find(|part| part.contains("name=\"links/latest\""))
+            .unwrap();
+        assert!(link_part.contains("filename=\"links/latest\""));
+        assert!(link_part.contains("Content-Type: application/javascript"));
+        assert!(link_part.ends_with("\r\n\r\nconsole.log(123);"));
+        // the dangling link is left out with a warning, not an error
+        assert!(body
+            .warnings
+            .iter()
+            .any(|warning| warning.starts_with("links/dangling couldn't be read")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_link_whose_target_goes_after_the_walk_is_skipped() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("site");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "<p>hi</p>").unwrap();
+        std::fs::write(root.join("target.js"), "1;").unwrap();
+        symlink("target.js", root.join("latest")).unwrap();
+        let dir = root.to_str().unwrap();
+        let files = vec![(dir, root.join("index.html")), (dir, root.join("latest"))];
+        std::fs::remove_file(root.join("target.js")).unwrap();
+
+        let body = form_from_files(&options(&[dir]), files).unwrap();
+        assert_eq!(body.entries.len(), 1);
+        assert_eq!(body.skipped.len(), 1);
+        assert!(body.skipped[0].1.contains("is a symlink to a missing file"));
+    }
+
+    #[test]
+    fn a_lockfile_from_another_hash_algorithm_isnt_compared() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(lockfile::PATH);
+        let path = path.to_str().unwrap();
+        let mut hashes = BTreeMap::new();
+        hashes.insert("app.js".to_string(), "aaaa".to_string());
+        lockfile::write(path, HashAlgorithm::Blake3, &hashes, false).unwrap();
+
+        let mut opts = options(&["site"]);
+        opts.lockfile = Some(path);
+        let mut changed = BTreeMap::new();
+        changed.insert("app.js".to_string(), "bbbb".to_string());
+        let mut warnings = vec![];
+        check_lockfile(&opts, &changed, &mut warnings).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("records blake3 hashes, not sha256"));
+
+        opts.frozen = true;
+        match check_lockfile(&opts, &changed, &mut vec![]) {
+            Err(DarkError::LockfileHashAlgorithm(_, locked, used)) => {
+                assert_eq!((locked.as_str(), used.as_str()), ("blake3", "sha256"))
+            }
+            other => panic!("expected LockfileHashAlgorithm, got {:?}", other),
+        }
+
+        // an upload with sha256 replaces the blake3 hashes rather than merging with them
+        lockfile::write(path, HashAlgorithm::Sha256, &changed, true).unwrap();
+        let locked = lockfile::read(path).unwrap().unwrap();
+        assert_eq!(locked.hash_algorithm, "sha256");
+        assert_eq!(locked.files, changed);
+    }
+
+    fn entry(name: &str, hash: &str) -> UploadEntry {
+        UploadEntry {
+            name: name.to_string(),
+            renamed_from: None,
+            path: PathBuf::from(name),
+            size: 1,
+            hash: Some(hash.to_string()),
+            normalized: vec![],
         }
     }
+
+    #[test]
+    fn a_partly_failed_upload_records_only_the_groups_that_got_through() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(lockfile::PATH);
+        let path = path.to_str().unwrap();
+        // the lockfile from an earlier deploy, which the failed group's file keeps
+        let mut locked = BTreeMap::new();
+        locked.insert("bad/b.js".to_string(), "old-b".to_string());
+        lockfile::write(path, HashAlgorithm::Sha256, &locked, false).unwrap();
+
+        let mut opts = options(&["site"]);
+        opts.group_by_directory = true;
+        opts.lockfile = Some(path);
+        let entries = vec![entry("ok/a.js", "new-a"), entry("bad/b.js", "new-b")];
+        let hashes = entries
+            .iter()
+            .map(|entry| (entry.name.clone(), entry.hash.clone().unwrap()))
+            .collect();
+        let failed = DarkError::GroupsFailed(1, 2, String::new(), vec!["bad".to_string()]);
+
+        after_outcome(&opts, &entries, &hashes, Err(&failed), true).unwrap();
+        let locked = lockfile::read(path).unwrap().unwrap().files;
+        assert_eq!(locked.get("ok/a.js").map(String::as_str), Some("new-a"));
+        assert_eq!(locked.get("bad/b.js").map(String::as_str), Some("old-b"));
+
+        // an upload that failed outright records nothing
+        std::fs::remove_file(path).unwrap();
+        after_outcome(&opts, &entries, &hashes, Err(&DarkError::Unknown), true).unwrap();
+        assert!(lockfile::read(path).unwrap().is_none());
+    }
 }
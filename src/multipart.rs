@@ -0,0 +1,525 @@
+// A small multipart/form-data encoder. We build the body ourselves rather than using reqwest's
+// multipart::Form because we need to get at the encoded bytes - to stream them through gzip for
+// --compress - and reqwest keeps its form reader private.
+//
+// The output matches what reqwest produced with percent_encode_noop(): each part is
+// "--boundary\r\n", its headers, a blank line, its contents and "\r\n", and the body ends with
+// "--boundary--\r\n".
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher as _};
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hash::{HashAlgorithm, Hasher};
+
+#[derive(Debug)]
+enum Source {
+    // Opened only when the body is read, so building a form doesn't hold a file open per part.
+    File(PathBuf),
+    // A file's contents, changed in memory (by --normalize-text) from what's on disk at the path.
+    FileContents(PathBuf, Vec<u8>),
+    // A file read from stdin (--stdin-name).
+    Stdin(Vec<u8>),
+    Bytes(Vec<u8>),
+}
+
+// The mime type for a file, guessed from `path`'s extension the way reqwest does.
+pub(crate) fn guess_mime(path: &Path) -> String {
+    let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    mime_guess::get_mime_type_str(ext)
+        .unwrap_or("application/octet-stream")
+        .to_string()
+}
+
+#[derive(Debug)]
+pub(crate) struct Part {
+    name: String,
+    file_name: Option<String>,
+    mime: Option<String>,
+    source: Source,
+    len: u64,
+}
+
+impl Part {
+    // A file part; the mime type is guessed from `path`'s extension, the way reqwest does.
+    pub(crate) fn file(path: &Path, file_name: &str, len: u64) -> Part {
+        Part::file_from(path, file_name, Source::File(path.to_path_buf()), len)
+    }
+
+    // A file part that sends `contents` in place of what's on disk at `path`.
+    pub(crate) fn file_contents(path: &Path, file_name: &str, contents: Vec<u8>) -> Part {
+        let len = contents.len() as u64;
+        Part::file_from(
+            path,
+            file_name,
+            Source::FileContents(path.to_path_buf(), contents),
+            len,
+        )
+    }
+
+    // A file part for `contents` read from stdin, with `mime` as its type.
+    pub(crate) fn stdin(file_name: &str, contents: Vec<u8>, mime: &str) -> Part {
+        Part {
+            name: String::new(),
+            file_name: Some(file_name.to_string()),
+            mime: Some(mime.to_string()),
+            len: contents.len() as u64,
+            source: Source::Stdin(contents),
+        }
+    }
+
+    fn file_from(path: &Path, file_name: &str, source: Source, len: u64) -> Part {
+        Part {
+            name: String::new(),
+            file_name: Some(file_name.to_string()),
+            mime: Some(guess_mime(path)),
+            source,
+            len,
+        }
+    }
+
+    pub(crate) fn text(value: String) -> Part {
+        Part {
+            name: String::new(),
+            file_name: None,
+            mime: None,
+            len: value.len() as u64,
+            source: Source::Bytes(value.into_bytes()),
+        }
+    }
+
+    fn headers(&self, boundary: &str) -> Vec<u8> {
+        let mut headers = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"",
+            boundary,
+            quote_param(&self.name)
+        );
+        if let Some(ref file_name) = self.file_name {
+            headers.push_str(&format!("; filename=\"{}\"", quote_param(file_name)));
+        }
+        if let Some(ref mime) = self.mime {
+            headers.push_str(&format!("\r\nContent-Type: {}", mime));
+        }
+        headers.push_str("\r\n\r\n");
+        headers.into_bytes()
+    }
+}
+
+// `value` made safe to put between the double quotes of a header parameter (and of curl's -F
+// syntax, which reads them the same way): '"' and '\\' are backslash-escaped, and CR and LF, which
+// can't appear in a header even quoted, are percent-encoded the way browsers send them.
+fn quote_param(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\r' => quoted.push_str("%0D"),
+            '\n' => quoted.push_str("%0A"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted
+}
+
+// The digests of the files a form's reader has sent in full, by path.
+type SentHashes = Arc<Mutex<BTreeMap<PathBuf, String>>>;
+
+#[derive(Debug)]
+pub(crate) struct Form {
+    boundary: String,
+    parts: Vec<Part>,
+    // with hash_as_sent, what to hash the files with, and their digests once they've been read
+    hash_as_sent: Option<(HashAlgorithm, SentHashes)>,
+}
+
+// reqwest's boundaries are random hex too; RandomState gives us per-process random keys without
+// pulling in a rand crate.
+fn gen_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.subsec_nanos())
+        .unwrap_or(0);
+    let random = |salt: u64| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(salt);
+        hasher.write_u32(nanos);
+        hasher.finish()
+    };
+    format!("{:016x}{:016x}", random(0), random(1))
+}
+
+// Whether `boundary` is one RFC 2046 allows: 1 to 70 letters, digits or '()+_,-./:=? and spaces,
+// not ending in a space.
+pub(crate) fn check_boundary(boundary: &str) -> Result<(), String> {
+    if boundary.is_empty() || boundary.len() > 70 {
+        return Err("must be 1 to 70 characters long".to_string());
+    }
+    if let Some(c) = boundary
+        .chars()
+        .find(|&c| !(c.is_ascii_alphanumeric() || "'()+_,-./:=? ".contains(c)))
+    {
+        return Err(format!("{:?} isn't allowed in a boundary", c));
+    }
+    if boundary.ends_with(' ') {
+        return Err("can't end in a space".to_string());
+    }
+    Ok(())
+}
+
+impl Form {
+    pub(crate) fn new() -> Form {
+        Form::with_boundary(gen_boundary())
+    }
+
+    // A form with a fixed boundary, for --multipart-boundary; check it with check_boundary first.
+    pub(crate) fn with_boundary(boundary: String) -> Form {
+        Form {
+            boundary,
+            parts: vec![],
+            hash_as_sent: None,
+        }
+    }
+
+    pub(crate) fn part(mut self, name: String, mut part: Part) -> Form {
+        part.name = name;
+        self.parts.push(part);
+        self
+    }
+
+    pub(crate) fn text(self, name: String, value: String) -> Form {
+        self.part(name, Part::text(value))
+    }
+
+    // Hash each file with `algorithm` as the reader streams it from disk, for what only needs
+    // the digests after the upload (see sent_hash), so the files aren't read a second time.
+    pub(crate) fn hash_as_sent(mut self, algorithm: HashAlgorithm) -> Form {
+        self.hash_as_sent = Some((algorithm, Arc::new(Mutex::new(BTreeMap::new()))));
+        self
+    }
+
+    // With hash_as_sent, the digest of the file at `path`, if a reader has sent all of it.
+    pub(crate) fn sent_hash(&self, path: &Path) -> Option<String> {
+        let (_, ref sent) = *self.hash_as_sent.as_ref()?;
+        let sent = sent.lock().ok()?;
+        sent.get(path).cloned()
+    }
+
+    pub(crate) fn content_type(&self) -> String {
+        // A boundary from --multipart-boundary may have characters that have to be quoted in a
+        // header parameter; our random ones never do.
+        if self
+            .boundary
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "'+_-.".contains(c))
+        {
+            format!("multipart/form-data; boundary={}", self.boundary)
+        } else {
+            format!("multipart/form-data; boundary=\"{}\"", self.boundary)
+        }
+    }
+
+    // The form as curl arguments (unquoted), for --print-request-curl: -F for files, and
+    // --form-string for text, which curl would otherwise parse for @ and ;. Files changed in
+    // memory are sent by curl as they are on disk.
+    pub(crate) fn curl_fields(&self) -> Vec<(&'static str, String)> {
+        self.parts
+            .iter()
+            .map(|part| match part.source {
+                Source::File(ref path) | Source::FileContents(ref path, _) => (
+                    "-F",
+                    format!(
+                        "{}=@\"{}\";filename=\"{}\";type={}",
+                        part.name,
+                        quote_param(&path.display().to_string()),
+                        quote_param(part.file_name.as_ref().unwrap_or(&part.name)),
+                        part.mime
+                            .as_ref()
+                            .map_or("application/octet-stream", String::as_str)
+                    ),
+                ),
+                Source::Stdin(_) => (
+                    "-F",
+                    format!(
+                        "{}=@-;filename=\"{}\";type={}",
+                        part.name,
+                        quote_param(part.file_name.as_ref().unwrap_or(&part.name)),
+                        part.mime
+                            .as_ref()
+                            .map_or("application/octet-stream", String::as_str)
+                    ),
+                ),
+                Source::Bytes(ref bytes) => (
+                    "--form-string",
+                    format!("{}={}", part.name, String::from_utf8_lossy(bytes)),
+                ),
+            })
+            .collect()
+    }
+
+    // The exact length of the encoded body.
+    pub(crate) fn len(&self) -> u64 {
+        let parts: u64 = self
+            .parts
+            .iter()
+            .map(|part| part.headers(&self.boundary).len() as u64 + part.len + 2)
+            .sum();
+        parts + self.boundary.len() as u64 + 6
+    }
+
+    // The first file that's no longer the size it was when it was added, with that size and its
+    // size now: checked before sending, since the body's length was worked out from those sizes.
+    pub(crate) fn changed_file(&self) -> Option<(PathBuf, u64, u64)> {
+        self.parts.iter().find_map(|part| match part.source {
+            Source::File(ref path) => {
+                let now = path.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+                if now == part.len {
+                    None
+                } else {
+                    Some((path.clone(), part.len, now))
+                }
+            }
+            _ => None,
+        })
+    }
+
+    // A reader for the encoded body. Each call starts from the beginning, so a failed upload can
+    // be sent again.
+    pub(crate) fn reader(&self) -> Reader {
+        let mut segments = VecDeque::new();
+        for part in &self.parts {
+            segments.push_back(Segment::Bytes(part.headers(&self.boundary)));
+            segments.push_back(match part.source {
+                Source::File(ref path) => Segment::File(path.clone(), part.len),
+                Source::FileContents(_, ref bytes)
+                | Source::Stdin(ref bytes)
+                | Source::Bytes(ref bytes) => Segment::Bytes(bytes.clone()),
+            });
+            segments.push_back(Segment::Bytes(b"\r\n".to_vec()));
+        }
+        segments.push_back(Segment::Bytes(
+            format!("--{}--\r\n", self.boundary).into_bytes(),
+        ));
+        Reader {
+            segments,
+            current: None,
+            hash_as_sent: self.hash_as_sent.clone(),
+        }
+    }
+}
+
+enum Segment {
+    Bytes(Vec<u8>),
+    // a file, and the size it had when the form was built
+    File(PathBuf, u64),
+}
+
+fn changed_size(path: &Path, len: u64, now: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{} changed size while uploading ({} bytes when found, {} now)",
+            path.display(),
+            len,
+            now
+        ),
+    )
+}
+
+// A file's contents, which must come to exactly the size it was found with: the body's
+// Content-Length was worked out from it, so a file that has since grown or shrunk fails the read
+// rather than sending a body that's cut short or runs over.
+// With Form::hash_as_sent, it's hashed as it goes, and the digest recorded once it's all read.
+struct SizedFile {
+    path: PathBuf,
+    len: u64,
+    file: io::Take<File>,
+    hashing: Option<(Hasher, SentHashes)>,
+}
+
+impl SizedFile {
+    fn open(
+        path: PathBuf,
+        len: u64,
+        hash_as_sent: &Option<(HashAlgorithm, SentHashes)>,
+    ) -> io::Result<SizedFile> {
+        let file = File::open(&path)?;
+        let now = file.metadata()?.len();
+        if now != len {
+            return Err(changed_size(&path, len, now));
+        }
+        let mut sized = SizedFile {
+            path,
+            len,
+            file: file.take(len),
+            hashing: hash_as_sent
+                .as_ref()
+                .map(|&(algorithm, ref sent)| (Hasher::new(algorithm), sent.clone())),
+        };
+        // an empty file is all read as soon as it's open
+        sized.record_hash();
+        Ok(sized)
+    }
+
+    fn record_hash(&mut self) {
+        if self.file.limit() > 0 {
+            return;
+        }
+        if let Some((hasher, sent)) = self.hashing.take() {
+            if let Ok(mut sent) = sent.lock() {
+                sent.insert(self.path.clone(), hasher.hex());
+            }
+        }
+    }
+}
+
+impl Read for SizedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read(buf)?;
+        if read == 0 && !buf.is_empty() && self.file.limit() > 0 {
+            let now = self.len - self.file.limit();
+            return Err(changed_size(&self.path, self.len, now));
+        }
+        if let Some((ref mut hasher, _)) = self.hashing {
+            hasher.update(&buf[..read]);
+        }
+        self.record_hash();
+        Ok(read)
+    }
+}
+
+// Reads the encoded body, opening each file only once the reader gets to it and dropping it as
+// soon as it's done.
+pub(crate) struct Reader {
+    segments: VecDeque<Segment>,
+    current: Option<Box<dyn Read + Send>>,
+    hash_as_sent: Option<(HashAlgorithm, SentHashes)>,
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.current.is_none() {
+                self.current = match self.segments.pop_front() {
+                    Some(Segment::Bytes(bytes)) => Some(Box::new(Cursor::new(bytes))),
+                    Some(Segment::File(path, len)) => {
+                        Some(Box::new(SizedFile::open(path, len, &self.hash_as_sent)?))
+                    }
+                    None => return Ok(0),
+                };
+            }
+            let read = match self.current {
+                Some(ref mut current) => current.read(buf)?,
+                None => 0,
+            };
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            self.current = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boundaries_are_checked_against_rfc_2046() {
+        assert_eq!(check_boundary("simple-boundary_1.2"), Ok(()));
+        assert_eq!(check_boundary("with 'quotes' (and) spaces?"), Ok(()));
+        assert!(check_boundary(&"x".repeat(70)).is_ok());
+        assert!(check_boundary(&"x".repeat(71)).is_err());
+        assert!(check_boundary("").is_err());
+        assert_eq!(
+            check_boundary("semi;colon"),
+            Err("';' isn't allowed in a boundary".to_string())
+        );
+        assert_eq!(
+            check_boundary("trailing "),
+            Err("can't end in a space".to_string())
+        );
+        assert_eq!(
+            Form::with_boundary("plain-1".to_string()).content_type(),
+            "multipart/form-data; boundary=plain-1"
+        );
+        assert_eq!(
+            Form::with_boundary("needs quoting?".to_string()).content_type(),
+            "multipart/form-data; boundary=\"needs quoting?\""
+        );
+    }
+
+    #[test]
+    fn quotes_and_line_breaks_in_names_cant_escape_the_header() {
+        let name = "a\"b\\c\r\nX-Injected: 1.txt";
+        let form = Form::with_boundary("boundary".to_string()).part(
+            name.to_string(),
+            Part::stdin(name, b"hi".to_vec(), "text/plain"),
+        );
+        let mut body = String::new();
+        form.reader().read_to_string(&mut body).unwrap();
+        let escaped = "a\\\"b\\\\c%0D%0AX-Injected: 1.txt";
+        assert_eq!(
+            body,
+            format!(
+                "--boundary\r\nContent-Disposition: form-data; name=\"{0}\"; filename=\"{0}\"\r\n\
+                 Content-Type: text/plain\r\n\r\nhi\r\n--boundary--\r\n",
+                escaped
+            )
+        );
+        assert_eq!(body.len() as u64, form.len());
+        assert_eq!(
+            form.curl_fields(),
+            vec![(
+                "-F",
+                format!("{}=@-;filename=\"{}\";type=text/plain", name, escaped)
+            )]
+        );
+    }
+
+    #[test]
+    fn a_file_that_changes_size_after_the_form_is_built_fails_the_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("app.js");
+        std::fs::write(&path, "let a;").unwrap();
+        let form = Form::new().part("app.js".to_string(), Part::file(&path, "app.js", 6));
+        assert!(form.changed_file().is_none());
+        assert!(form.reader().read_to_end(&mut vec![]).is_ok());
+
+        for contents in &["let a = 1;", "let"] {
+            std::fs::write(&path, contents).unwrap();
+            assert_eq!(
+                form.changed_file(),
+                Some((path.clone(), 6, contents.len() as u64))
+            );
+            let err = form.reader().read_to_end(&mut vec![]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn files_are_hashed_as_theyre_sent() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (full, empty) = (tmp.path().join("app.js"), tmp.path().join("empty.txt"));
+        std::fs::write(&full, "let a;").unwrap();
+        std::fs::write(&empty, "").unwrap();
+        let form = Form::new()
+            .hash_as_sent(HashAlgorithm::Sha256)
+            .part("app.js".to_string(), Part::file(&full, "app.js", 6))
+            .part("empty.txt".to_string(), Part::file(&empty, "empty.txt", 0));
+        assert_eq!(form.sent_hash(&full), None);
+        form.reader().read_to_end(&mut vec![]).unwrap();
+        assert_eq!(
+            form.sent_hash(&full),
+            Some(HashAlgorithm::Sha256.hex(b"let a;"))
+        );
+        assert_eq!(form.sent_hash(&empty), Some(HashAlgorithm::Sha256.hex(b"")));
+    }
+}
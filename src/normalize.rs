@@ -0,0 +1,69 @@
+// --normalize-text: strip a UTF-8 byte order mark and turn CRLF line endings into LF in text
+// files, which editors on Windows tend to leave behind and which break some downstream tools.
+// Only the copy we upload is changed, never the file on disk.
+
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// Whether `contents` look like text: valid UTF-8 with no NUL bytes. Any binary format worth
+// worrying about (images, fonts, archives) fails one or the other almost immediately.
+fn is_text(contents: &[u8]) -> bool {
+    !contents.contains(&0) && std::str::from_utf8(contents).is_ok()
+}
+
+// The normalized contents of a file, and what was changed (e.g. ["stripped BOM", "CRLF -> LF"]);
+// None if it isn't text, or is already normalized.
+pub(crate) fn normalize(contents: &[u8]) -> Option<(Vec<u8>, Vec<&'static str>)> {
+    if !is_text(contents) {
+        return None;
+    }
+    let mut changes = vec![];
+    let contents = if contents.starts_with(BOM) {
+        changes.push("stripped BOM");
+        &contents[BOM.len()..]
+    } else {
+        contents
+    };
+    let mut normalized = Vec::with_capacity(contents.len());
+    let mut bytes = contents.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'\r' && bytes.peek() == Some(&&b'\n') {
+            continue;
+        }
+        normalized.push(byte);
+    }
+    if normalized.len() < contents.len() {
+        changes.push("CRLF -> LF");
+    }
+    if changes.is_empty() {
+        None
+    } else {
+        Some((normalized, changes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_a_bom_and_crlfs_from_text_only() {
+        assert_eq!(
+            normalize(b"\xEF\xBB\xBFa\r\nb\r\n"),
+            Some((b"a\nb\n".to_vec(), vec!["stripped BOM", "CRLF -> LF"]))
+        );
+        assert_eq!(
+            normalize(b"\xEF\xBB\xBFa\n"),
+            Some((b"a\n".to_vec(), vec!["stripped BOM"]))
+        );
+        // a lone CR isn't a line ending to change
+        assert_eq!(
+            normalize(b"a\rb\r\n"),
+            Some((b"a\rb\n".to_vec(), vec!["CRLF -> LF"]))
+        );
+        assert_eq!(normalize(b"already\nfine\n"), None);
+        assert_eq!(normalize(b""), None);
+        // binary: a NUL byte, or bytes that aren't UTF-8
+        assert_eq!(normalize(b"\x89PNG\r\n\x1a\n\0"), None);
+        assert_eq!(normalize(b"\xff\xfe\r\n"), None);
+    }
+}
@@ -0,0 +1,227 @@
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use {
+    hash_file, secrets, utc_now, write_atomically, DarkError, UploadEntry, UploadLog, UploadOptions,
+};
+
+#[derive(Serialize)]
+struct ReportConfig {
+    host: String,
+    canvas: String,
+    dirs: Vec<String>,
+    dry_run: bool,
+    strict: bool,
+    field_name: Option<String>,
+    csrf_header: String,
+    credentials: String,
+    // what the files' `hash`es are (--hash-algorithm)
+    hash_algorithm: String,
+}
+
+#[derive(Serialize)]
+struct ReportFile {
+    name: String,
+    size: u64,
+    hash: String,
+}
+
+// What --report writes: enough to answer "what was deployed, from where, and did it work" after
+// the terminal output is long gone.
+#[derive(Serialize)]
+pub(crate) struct Report {
+    generated_at: String,
+    args: Vec<String>,
+    config: ReportConfig,
+    files: Vec<ReportFile>,
+    outcome: String,
+    duration_secs: f64,
+    warnings: Vec<String>,
+}
+
+// The invocation, with the values of secret flags (see secrets::is_secret_flag) redacted, whether
+// they're given as `--flag value` or `--flag=value` (or, for -H, `-Hvalue`).
+fn redacted_args<I: IntoIterator<Item = String>>(args: I) -> Vec<String> {
+    let mut secret_flag: Option<String> = None;
+    args.into_iter()
+        .map(|arg| {
+            if let Some(flag) = secret_flag.take() {
+                return secrets::redact_flag_value(&flag, &arg);
+            }
+            if secrets::is_secret_flag(&arg) {
+                secret_flag = Some(arg.clone());
+                return arg;
+            }
+            let (flag, value) = if arg.starts_with("--") {
+                match arg.find('=') {
+                    Some(eq) => (&arg[..eq], &arg[eq + 1..]),
+                    None => return arg,
+                }
+            } else if arg.starts_with("-H") {
+                ("-H", arg[2..].trim_start_matches('='))
+            } else {
+                return arg;
+            };
+            if !secrets::is_secret_flag(flag) {
+                return arg;
+            }
+            format!(
+                "{}{}",
+                &arg[..arg.len() - value.len()],
+                secrets::redact_flag_value(flag, value)
+            )
+        })
+        .collect()
+}
+
+impl Report {
+    pub(crate) fn new(
+        opts: &UploadOptions,
+        credentials: &str,
+        log: &UploadLog,
+        outcome: Result<(), &DarkError>,
+        duration: Duration,
+    ) -> Report {
+        Report {
+            generated_at: utc_now(),
+            args: redacted_args(std::env::args()),
+            config: ReportConfig {
+                host: opts.host.to_string(),
+                canvas: opts.canvas.clone(),
+                dirs: opts.dirs.iter().map(|&dir| dir.to_string()).collect(),
+                dry_run: opts.dryrun,
+                strict: opts.strict,
+                field_name: opts.field_name.map(str::to_string),
+                csrf_header: opts.csrf_header.to_string(),
+                credentials: credentials.to_string(),
+                hash_algorithm: opts.hash_algorithm.name().to_string(),
+            },
+            files: log
+                .entries
+                .iter()
+                .map(|entry: &UploadEntry| ReportFile {
+                    name: entry.name.clone(),
+                    size: entry.size,
+                    // the digest of what was sent; one that wasn't all sent is read from disk
+                    hash: entry.hash.clone().unwrap_or_else(|| {
+                        hash_file(opts.hash_algorithm, &entry.path)
+                            .unwrap_or_else(|_| "<unreadable>".to_string())
+                    }),
+                })
+                .collect(),
+            outcome: match outcome {
+                Ok(()) if opts.dryrun => "dry run (nothing uploaded)".to_string(),
+                Ok(()) => "succeeded".to_string(),
+                Err(err) => format!("failed: {}", err),
+            },
+            duration_secs: duration.as_secs_f64(),
+            warnings: log.warnings.clone(),
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = format!(
+            "dark-cli upload report, generated {}\n\n",
+            self.generated_at
+        );
+        text.push_str(&format!("Invocation: {}\n\n", self.args.join(" ")));
+        text.push_str(&format!(
+            "Host: {}\nCanvas: {}\nDirs: {}\nDry run: {}\nStrict: {}\nField name: {}\nCSRF header: {}\nCredentials: {}\nHash algorithm: {}\n\n",
+            self.config.host,
+            self.config.canvas,
+            self.config.dirs.join(", "),
+            self.config.dry_run,
+            self.config.strict,
+            self.config.field_name.as_ref().map(String::as_str).unwrap_or("<per file>"),
+            self.config.csrf_header,
+            self.config.credentials,
+            self.config.hash_algorithm,
+        ));
+        text.push_str(&format!("Files ({}):\n", self.files.len()));
+        for file in &self.files {
+            text.push_str(&format!(
+                "  {}  {:>10}  {}\n",
+                file.hash, file.size, file.name
+            ));
+        }
+        text.push_str(&format!("\nWarnings ({}):\n", self.warnings.len()));
+        for warning in &self.warnings {
+            text.push_str(&format!("  {}\n", warning));
+        }
+        text.push_str(&format!(
+            "\nOutcome: {}\nDuration: {:.2}s\n",
+            self.outcome, self.duration_secs
+        ));
+        text
+    }
+
+    // Write the report to `path`: JSON if it ends in .json, readable text otherwise.
+    pub(crate) fn write(&self, path: &str) -> Result<(), DarkError> {
+        let contents = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::to_string_pretty(self)
+                .map_err(|err| DarkError::Report(path.to_string(), err.to_string()))?,
+            _ => self.to_text(),
+        };
+        write_atomically(Path::new(path), contents.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_flag_values_are_redacted_whichever_way_theyre_given() {
+        let args = [
+            "dark-cli",
+            "--password",
+            "hunter2",
+            "--password=hunter2",
+            "--purge-token",
+            "tok",
+            "--purge-token=tok",
+            "--auth-command",
+            "pass show dark",
+            "--auth-command=pass show dark",
+            "--header",
+            "Authorization: Bearer abc",
+            "--header=Authorization: Bearer abc",
+            "-H",
+            "X-Api-Key:abc",
+            "-HX-Api-Key: abc",
+            "-H=no-colon",
+            "--canvas",
+            "mycanvas",
+            "--report=report.json",
+            "dist",
+        ];
+        assert_eq!(
+            redacted_args(args.iter().map(|&arg| arg.to_string())),
+            vec![
+                "dark-cli",
+                "--password",
+                "<redacted>",
+                "--password=<redacted>",
+                "--purge-token",
+                "<redacted>",
+                "--purge-token=<redacted>",
+                "--auth-command",
+                "<redacted>",
+                "--auth-command=<redacted>",
+                "--header",
+                "Authorization: <redacted>",
+                "--header=Authorization: <redacted>",
+                "-H",
+                "X-Api-Key: <redacted>",
+                "-HX-Api-Key: <redacted>",
+                "-H=<redacted>",
+                "--canvas",
+                "mycanvas",
+                "--report=report.json",
+                "dist",
+            ]
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use reqwest::{Response, StatusCode};
+
+use warning;
+
+// How many times a request is retried after the first attempt fails.
+const MAX_RETRIES: u32 = 3;
+
+// Whether backoff delays are randomized; see set_jitter.
+static JITTER: AtomicBool = AtomicBool::new(true);
+
+// Turn backoff jitter on (the default) or off (--no-jitter). Jitter keeps many clients that
+// failed together from retrying in lockstep; without it the delays are exactly 1s, 2s, 4s, which
+// makes retries reproducible.
+pub(crate) fn set_jitter(jitter: bool) {
+    JITTER.store(jitter, Ordering::SeqCst);
+}
+
+// The delay before retry number `retry` (from 1): 1s, 2s, 4s, or with jitter, anywhere from half
+// to one and a half times that.
+fn delay(retry: u32) -> Duration {
+    let base = Duration::from_secs(1 << (retry - 1));
+    if !JITTER.load(Ordering::SeqCst) {
+        return base;
+    }
+    // RandomState is seeded randomly per instance, which is all the randomness this needs
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(retry);
+    let base_ms = base.as_millis() as u64;
+    Duration::from_millis(base_ms / 2 + hasher.finish() % base_ms)
+}
+
+// Whether a request that ended this way is worth sending again: the server couldn't be reached,
+// the connection dropped or timed out, or the server said it's overloaded or broken (5xx, 429).
+// Every other 4xx means the request itself is wrong, so sending it again won't help.
+pub(crate) fn should_retry(result: &Result<Response, reqwest::Error>) -> bool {
+    match result {
+        Ok(resp) => {
+            resp.status().is_server_error() || resp.status() == StatusCode::TOO_MANY_REQUESTS
+        }
+        Err(error) if error.is_timeout() => true,
+        Err(error) => match error
+            .get_ref()
+            .and_then(|cause| cause.downcast_ref::<hyper::Error>())
+        {
+            // A malformed response or a request we couldn't build (say, a file we couldn't read
+            // for the body) fails the same way every time.
+            Some(cause) => !(cause.is_parse() || (cause.is_user() && !cause.is_closed())),
+            None => false,
+        },
+    }
+}
+
+// Why `result` is being retried, for the warning.
+fn describe(result: &Result<Response, reqwest::Error>) -> String {
+    match result {
+        Ok(resp) => format!("the server responded {}", resp.status()),
+        Err(error) => error.to_string(),
+    }
+}
+
+// Call `send` until it gives a result should_retry doesn't want to retry, or we run out of
+// retries, backing off (about) 1s, 2s, 4s between attempts. `what` names the request in warnings.
+pub(crate) fn with_retries<F>(what: &str, mut send: F) -> Result<Response, reqwest::Error>
+where
+    F: FnMut() -> Result<Response, reqwest::Error>,
+{
+    let mut retries = 0;
+    loop {
+        let result = send();
+        if retries == MAX_RETRIES || !should_retry(&result) {
+            return result;
+        }
+        retries += 1;
+        let delay = delay(retries);
+        warning::push(format!(
+            "{} failed ({}); retried in {:.1}s ({} of {}).",
+            what,
+            describe(&result),
+            delay.as_secs_f64(),
+            retries,
+            MAX_RETRIES
+        ));
+        thread::sleep(delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{self, Read, Write};
+    use std::net::TcpListener;
+
+    fn response(status: u16) -> Result<Response, reqwest::Error> {
+        Ok(Response::from(
+            http::Response::builder().status(status).body("").unwrap(),
+        ))
+    }
+
+    // A server on a local port that handles one connection with `handle`, and its URL.
+    fn serve<F>(handle: F) -> String
+    where
+        F: FnOnce(std::net::TcpStream) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                handle(stream);
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn retries_server_errors_and_429_only() {
+        for &status in &[500, 502, 503, 504, 429] {
+            assert!(
+                should_retry(&response(status)),
+                "{} should be retried",
+                status
+            );
+        }
+        for &status in &[
+            200, 201, 204, 301, 302, 304, 400, 401, 403, 404, 409, 413, 422,
+        ] {
+            assert!(
+                !should_retry(&response(status)),
+                "{} shouldn't be retried",
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn retries_refused_connections() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        // nothing listens on the port once the listener is dropped
+        let result = reqwest::get(&format!("http://127.0.0.1:{}/", port));
+        assert!(result.is_err());
+        assert!(should_retry(&result));
+    }
+
+    #[test]
+    fn retries_timeouts() {
+        let url = serve(|stream| {
+            thread::sleep(Duration::from_secs(2));
+            drop(stream);
+        });
+        let result = reqwest::Client::builder()
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap()
+            .get(&url)
+            .send();
+        assert!(result.as_ref().err().map_or(false, |err| err.is_timeout()));
+        assert!(should_retry(&result));
+    }
+
+    #[test]
+    fn retries_dropped_connections() {
+        let url = serve(|mut stream| {
+            let _ = stream.read(&mut [0; 1024]);
+        });
+        let result = reqwest::get(&url);
+        assert!(result.is_err());
+        assert!(should_retry(&result));
+    }
+
+    #[test]
+    fn doesnt_retry_malformed_responses() {
+        let url = serve(|mut stream| {
+            let _ = stream.read(&mut [0; 1024]);
+            let _ = stream.write_all(b"this isn't HTTP\r\n\r\n");
+        });
+        let result = reqwest::get(&url);
+        assert!(result.is_err());
+        assert!(!should_retry(&result));
+    }
+
+    struct Unreadable;
+
+    impl Read for Unreadable {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "denied"))
+        }
+    }
+
+    #[test]
+    fn doesnt_retry_bodies_that_cant_be_read() {
+        let url = serve(|mut stream| {
+            let _ = stream.read(&mut [0; 1024]);
+            thread::sleep(Duration::from_millis(500));
+        });
+        let result = reqwest::Client::new()
+            .post(&url)
+            .body(reqwest::Body::sized(Unreadable, 10))
+            .send();
+        assert!(result.is_err());
+        assert!(!should_retry(&result));
+    }
+}
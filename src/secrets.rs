@@ -0,0 +1,77 @@
+// The session cookie, the CSRF token and --header values are as good as a password, so errors
+// and debug output (the --dry-run request dump, the debug-build trace of the login, the
+// invocation --report records) leave them out unless --show-secrets-in-errors asks for them
+// while troubleshooting locally.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use reqwest::header::{HeaderValue, InvalidHeaderValue};
+
+// What's printed in place of a secret.
+pub(crate) const REDACTED: &str = "<redacted>";
+
+// The flags whose values are secrets: the password, the purge token, the command that prints the
+// password, and the extra headers, which header_value marks sensitive whatever they are.
+const SECRET_FLAGS: &[&str] = &[
+    "--password",
+    "--purge-token",
+    "--auth-command",
+    "--header",
+    "-H",
+];
+
+// Whether secrets are printed; see set_shown.
+static SHOWN: AtomicBool = AtomicBool::new(false);
+
+// Print secrets in errors and debug output (--show-secrets-in-errors), or don't (the default).
+pub(crate) fn set_shown(shown: bool) {
+    SHOWN.store(shown, Ordering::SeqCst);
+}
+
+// `secret`, or REDACTED unless secrets are shown.
+pub(crate) fn redact(secret: &str) -> &str {
+    if SHOWN.load(Ordering::SeqCst) {
+        secret
+    } else {
+        REDACTED
+    }
+}
+
+// `text` (say, a response body going into an error) with any of `secrets` in it replaced by
+// REDACTED, unless secrets are shown.
+pub(crate) fn scrub(text: String, secrets: &[&str]) -> String {
+    if SHOWN.load(Ordering::SeqCst) {
+        return text;
+    }
+    secrets
+        .iter()
+        .filter(|secret| !secret.is_empty())
+        .fold(text, |text, secret| text.replace(secret, REDACTED))
+}
+
+// A header value carrying a secret. Unless secrets are shown it's marked sensitive, which makes
+// its Debug output (and so a debug print of the request it's in) "Sensitive" instead.
+pub(crate) fn header_value(value: &str) -> Result<HeaderValue, InvalidHeaderValue> {
+    let mut header_value = HeaderValue::from_str(value)?;
+    header_value.set_sensitive(!SHOWN.load(Ordering::SeqCst));
+    Ok(header_value)
+}
+
+// Whether `flag` takes a secret as its value (see SECRET_FLAGS).
+pub(crate) fn is_secret_flag(flag: &str) -> bool {
+    SECRET_FLAGS.contains(&flag)
+}
+
+// The value given to secret `flag`, as it's safe to record: REDACTED unless secrets are shown,
+// except that a header keeps its name (`Authorization: <redacted>`), like header_value's.
+pub(crate) fn redact_flag_value(flag: &str, value: &str) -> String {
+    if SHOWN.load(Ordering::SeqCst) {
+        return value.to_string();
+    }
+    match value.find(':') {
+        Some(colon) if flag == "--header" || flag == "-H" => {
+            format!("{}: {}", value[..colon].trim(), REDACTED)
+        }
+        _ => REDACTED.to_string(),
+    }
+}
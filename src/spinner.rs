@@ -0,0 +1,53 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const FRAMES: &[char] = &['|', '/', '-', '\\'];
+
+// Print `message` to stderr while something slow happens, so it stays out of output that's piped
+// or parsed. When stderr is a terminal it gets a spinner after it until it's dropped; otherwise
+// (redirected to a file or CI log) it's just printed once.
+pub(crate) struct Spinner {
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Spinner {
+    pub(crate) fn start(message: String) -> Spinner {
+        if !atty::is(atty::Stream::Stderr) {
+            eprintln!("{}", message);
+            return Spinner {
+                running: Arc::new(AtomicBool::new(false)),
+                thread: None,
+            };
+        }
+
+        let running = Arc::new(AtomicBool::new(true));
+        let still_running = running.clone();
+        let thread = thread::spawn(move || {
+            let mut frames = FRAMES.iter().cycle();
+            while still_running.load(Ordering::SeqCst) {
+                eprint!("\r{} {}", message, frames.next().unwrap_or(&' '));
+                let _ = io::stderr().flush();
+                thread::sleep(Duration::from_millis(100));
+            }
+            // leave the message, without the spinner
+            eprintln!("\r{}  ", message);
+        });
+        Spinner {
+            running,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use {warning, write_atomically, HashAlgorithm, UploadEntry};
+
+// What --since-last-deploy remembers about the last successful deploy to a canvas: when it was,
+// and the hash of every asset it (or an earlier deploy) uploaded, by asset name.
+#[derive(Deserialize, Serialize)]
+struct Deploy {
+    canvas: String,
+    deployed_at: u64,
+    // the --hash-algorithm the hashes were made with; records from before it was kept are sha256
+    #[serde(default = "default_hash_algorithm")]
+    hash_algorithm: String,
+    files: BTreeMap<String, String>,
+}
+
+fn default_hash_algorithm() -> String {
+    HashAlgorithm::Sha256.name().to_string()
+}
+
+// The state file: deploys keyed by canvas@host, so one canvas on dev and prod (or two canvases
+// built from the same tree) don't get mixed up.
+type Deploys = BTreeMap<String, Deploy>;
+
+fn path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("dark-cli").join("deploys.json"))
+}
+
+fn key(host: &str, canvas: &str) -> String {
+    format!("{}@{}", canvas, host)
+}
+
+// Read the state file. A missing file means nothing's been deployed yet; one we can't parse is
+// ignored, with a warning, and replaced by the next save.
+fn read() -> Deploys {
+    let path = match path() {
+        Some(path) => path,
+        None => return Deploys::new(),
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(_) => return Deploys::new(),
+    };
+    serde_json::from_str(&contents).unwrap_or_else(|err| {
+        warning::push(format!(
+            "ignored the unreadable deploy state {} ({}).",
+            path.display(),
+            err
+        ));
+        Deploys::new()
+    })
+}
+
+// The asset names and hashes from the last deploy to `canvas` on `host`, if we have a record of
+// one with `algorithm`'s hashes (another's wouldn't match anything).
+pub(crate) fn load(
+    host: &str,
+    canvas: &str,
+    algorithm: HashAlgorithm,
+) -> Option<BTreeMap<String, String>> {
+    read()
+        .remove(&key(host, canvas))
+        .filter(|deploy| deploy.canvas == canvas && deploy.hash_algorithm == algorithm.name())
+        .map(|deploy| deploy.files)
+}
+
+// Record a successful deploy of `entries` to `canvas` on `host`. Assets from earlier deploys stay
+// in the record, since a deploy that skipped unchanged files didn't remove them from the canvas.
+// Like the session cache, failing to save only costs a full upload next time, so it's a warning.
+pub(crate) fn save<'e, I>(host: &str, canvas: &str, algorithm: HashAlgorithm, entries: I)
+where
+    I: IntoIterator<Item = &'e UploadEntry>,
+{
+    let path = match path() {
+        Some(path) => path,
+        None => return,
+    };
+    let mut deploys = read();
+    let deploy = deploys.entry(key(host, canvas)).or_insert_with(|| Deploy {
+        canvas: canvas.to_string(),
+        deployed_at: 0,
+        hash_algorithm: algorithm.name().to_string(),
+        files: BTreeMap::new(),
+    });
+    // hashes made with another algorithm can't be compared with these, so they're dropped
+    if deploy.hash_algorithm != algorithm.name() {
+        deploy.hash_algorithm = algorithm.name().to_string();
+        deploy.files.clear();
+    }
+    deploy.deployed_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or(0);
+    for entry in entries {
+        if let Some(ref hash) = entry.hash {
+            deploy.files.insert(entry.name.clone(), hash.clone());
+        }
+    }
+    let contents = serde_json::to_string_pretty(&deploys).unwrap_or_default();
+    let saved = match path.parent() {
+        Some(dir) => fs::create_dir_all(dir).map_err(|err| err.to_string()),
+        None => Ok(()),
+    }
+    .and_then(|_| write_atomically(&path, contents.as_bytes()).map_err(|err| err.to_string()));
+    if let Err(err) = saved {
+        warning::push(format!("couldn't save the deploy state: {}", err));
+    }
+}
@@ -0,0 +1,20 @@
+// Warnings raised outside the walk (a retried request, a session cache or deploy state we
+// couldn't read or save, an error watching the dirs, a failed purge), held until the run prints
+// its warnings together at the end, so they're shown once and --warnings-as-errors counts them
+// like the walk's.
+
+use std::cell::RefCell;
+
+thread_local! {
+    static WARNINGS: RefCell<Vec<String>> = RefCell::new(vec![]);
+}
+
+// Hold `warning` for the end of the run.
+pub(crate) fn push(warning: String) {
+    WARNINGS.with(|warnings| warnings.borrow_mut().push(warning));
+}
+
+// The warnings held since the last call.
+pub(crate) fn take() -> Vec<String> {
+    WARNINGS.with(|warnings| warnings.replace(vec![]))
+}